@@ -1,19 +1,286 @@
-use std::ops::Deref;
+#![allow(clippy::needless_return)]
 use std::{env, fs, io};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use actix_cors::Cors;
 use actix_files as af;
-use actix_web::{get, web, App, HttpResponse, HttpServer, HttpRequest};
+use actix_web::{get, post, web, App, HttpResponse, HttpServer, HttpRequest, ResponseError};
+use actix_web::http::{header, StatusCode};
+use actix_web::body::SizedStream;
 use downloader::*;
+use jobs::{JobStore, JobState};
+use progress::ProgressEvent;
+use loudness::LoudnessTarget;
+use silence::SilenceSettings;
+use thumbnails::{ThumbnailFormat, ThumbnailOptions};
+use subtitles::SubtitleFormat;
+use streaming::StreamCache;
+use tokio::sync::broadcast::error::RecvError;
 use serde::{Serialize, Deserialize};
-use open;
-use envy;
 use dotenv::dotenv;
 
 #[derive(Debug, Deserialize)]
 pub struct DownloaderParams {
+    format: Option<String>,
+    bitrate: Option<String>,
+    quality: Option<String>,
+    normalize: Option<String>,
+    replaygain: Option<String>,
+    trim_silence: Option<String>,
+    split: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    height: Option<String>,
+    fps: Option<String>,
+    max_filesize: Option<String>,
+    vcodec: Option<String>,
+    acodec: Option<String>,
+    crf: Option<String>,
+    preset: Option<String>,
+    subs: Option<String>,
+    proxy: Option<String>
+}
+
+impl DownloaderParams {
+    fn media_format(&self) -> Result<MediaFormat, DownloaderError> {
+        let raw = self.format.as_deref().unwrap_or("mp3");
+
+        return MediaFormat::parse(raw)
+            .ok_or_else(|| DownloaderError::InvalidInput(format!("Unsupported format {:?}", raw)));
+    }
+
+    fn audio_options(&self) -> Result<AudioOptions, DownloaderError> {
+        let format = match self.media_format()? {
+            MediaFormat::Audio(format) => format,
+            MediaFormat::Video(_) => AudioFormat::default(),
+        };
+
+        let bitrate = match self.bitrate.as_deref() {
+            Some(raw) => {
+                let bitrate = raw.trim_end_matches(['k', 'K']).parse::<u16>().ok()
+                    .filter(|b| AUDIO_BITRATES.contains(b))
+                    .ok_or_else(|| DownloaderError::InvalidInput(format!("Unsupported bitrate {:?}, use one of {:?}", raw, AUDIO_BITRATES)))?;
+
+                Some(bitrate)
+            },
+            None => None,
+        };
+
+        let quality = match self.quality.as_deref() {
+            Some(raw) => Some(raw.strip_prefix(['v', 'V']).and_then(|q| q.parse::<u8>().ok())
+                .filter(|q| *q <= 9)
+                .ok_or_else(|| DownloaderError::InvalidInput(format!("Unsupported quality {:?}, use v0 to v9", raw)))?),
+            None => None,
+        };
+
+        if bitrate.is_some() && quality.is_some() {
+            return Err(DownloaderError::InvalidInput("Use either bitrate or quality, not both".to_string()));
+        }
+
+        let c : Configuration = envy::from_env::<Configuration>().expect("Provide config.");
+        let normalize = parse_flag("normalize", self.normalize.as_deref())?.unwrap_or(c.normalize_audio);
+        let normalize = normalize.then_some(LoudnessTarget {
+            integrated: c.loudnorm_target_lufs,
+            true_peak: c.loudnorm_true_peak,
+            lra: c.loudnorm_lra,
+        });
+        let replaygain = parse_flag("replaygain", self.replaygain.as_deref())?.unwrap_or(c.replaygain);
+        let split_chapters = match self.split.as_deref() {
+            Some("chapters") => true,
+            Some(other) => return Err(DownloaderError::InvalidInput(format!("Unsupported split {:?}, use chapters", other))),
+            None => false,
+        };
+
+        // Only an explicit trim_silence conflicts with split, the configured default is just dropped.
+        let trim_silence = parse_flag("trim_silence", self.trim_silence.as_deref())?;
+        let clip = self.clip()?;
+        if split_chapters && (clip.is_some() || trim_silence == Some(true)) {
+            return Err(DownloaderError::InvalidInput("split cannot be combined with start, end or trim_silence".to_string()));
+        }
+        let trim_silence = trim_silence.unwrap_or(c.trim_silence && !split_chapters);
+        let trim_silence = trim_silence.then_some(SilenceSettings {
+            threshold_db: c.silence_threshold_db,
+            min_duration: c.silence_min_duration,
+        });
+        if split_chapters && matches!(self.media_format()?, MediaFormat::Video(_)) {
+            return Err(DownloaderError::InvalidInput("split is only supported for audio formats".to_string()));
+        }
+
+        return AudioOptions { format, bitrate, quality, normalize, replaygain, trim_silence, split_chapters, clip }
+            .cap_bitrate(c.max_audio_bitrate);
+    }
+
+    /// Uses the `t=` of a pasted link as start unless `start` was given explicitly.
+    fn apply_link_start(&mut self, start: Option<u64>) {
+        if self.start.is_none() {
+            self.start = start.map(|s| s.to_string());
+        }
+    }
+
+    fn clip(&self) -> Result<Option<Clip>, DownloaderError> {
+        let parse = |name: &str, raw: &str| parse_timestamp(raw)
+            .ok_or_else(|| DownloaderError::InvalidInput(format!("Unsupported {} {:?}, use seconds or hh:mm:ss", name, raw)));
+
+        let start = match self.start.as_deref() {
+            Some(raw) => parse("start", raw)?,
+            None => 0,
+        };
+        let end = match self.end.as_deref() {
+            Some(raw) => Some(parse("end", raw)?),
+            None => None,
+        };
+
+        if end.is_some_and(|end| end <= start) {
+            return Err(DownloaderError::InvalidInput("end must be after start".to_string()));
+        }
+        if start == 0 && end.is_none() {
+            return Ok(None);
+        }
+
+        return Ok(Some(Clip { start, end }));
+    }
+
+    fn video_options(&self) -> Result<VideoOptions, DownloaderError> {
+        let height = match self.height.as_deref() {
+            Some(raw) => Some(raw.trim_end_matches(['p', 'P']).parse::<u16>().ok()
+                .filter(|h| VIDEO_HEIGHTS.contains(h))
+                .ok_or_else(|| DownloaderError::InvalidInput(format!("Unsupported height {:?}, use one of {:?}", raw, VIDEO_HEIGHTS)))?),
+            None => None,
+        };
+
+        let fps = match self.fps.as_deref() {
+            Some(raw) => Some(raw.parse::<u16>().ok()
+                .filter(|f| (1..=120).contains(f))
+                .ok_or_else(|| DownloaderError::InvalidInput(format!("Unsupported fps {:?}", raw)))?),
+            None => None,
+        };
+
+        let max_filesize = match self.max_filesize.as_deref() {
+            Some(raw) => Some(parse_filesize(raw)
+                .filter(|s| *s > 0)
+                .ok_or_else(|| DownloaderError::InvalidInput(format!("Unsupported max_filesize {:?}, use e.g. 50M or 1.5G", raw)))?),
+            None => None,
+        };
+
+        let container = match self.media_format()? {
+            MediaFormat::Video(container) => container,
+            MediaFormat::Audio(_) => VideoContainer::default(),
+        };
+
+        let codec = match self.vcodec.as_deref() {
+            Some(raw) => Some(VideoCodec::parse(raw)
+                .ok_or_else(|| DownloaderError::InvalidInput(format!("Unsupported video codec {:?}", raw)))?),
+            None => None,
+        };
+        if let Some(codec) = codec {
+            if !container.allows_video(codec.source_name()) {
+                return Err(DownloaderError::InvalidInput(format!("{} cannot hold {}", container.extension(), codec.source_name())));
+            }
+        }
+
+        let audio_codec = match self.acodec.as_deref() {
+            Some(raw) => Some(VideoAudioCodec::parse(raw)
+                .ok_or_else(|| DownloaderError::InvalidInput(format!("Unsupported audio codec {:?}, use aac or opus", raw)))?),
+            None => None,
+        };
+        if let Some(audio_codec) = audio_codec {
+            if !container.allows_audio(audio_codec.source_name()) {
+                return Err(DownloaderError::InvalidInput(format!("{} cannot hold {}", container.extension(), audio_codec.source_name())));
+            }
+        }
+
+        let codec_or_default = codec.unwrap_or(container.default_codec());
+        let crf = match self.crf.as_deref() {
+            Some(raw) => Some(raw.parse::<u8>().ok()
+                .filter(|c| *c <= codec_or_default.max_crf())
+                .ok_or_else(|| DownloaderError::InvalidInput(format!("Unsupported crf {:?} for {}, use 0 to {}", raw, codec_or_default.encoder(), codec_or_default.max_crf())))?),
+            None => None,
+        };
+        let preset = match self.preset.as_deref() {
+            Some(raw) => Some(codec_or_default.parse_preset(raw)
+                .ok_or_else(|| DownloaderError::InvalidInput(format!("Unsupported preset {:?} for {}", raw, codec_or_default.encoder())))?),
+            None => None,
+        };
+
+        let mut subtitles: Vec<String> = Vec::new();
+        for lang in self.subs.as_deref().unwrap_or_default().split(',').map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            if lang.len() > 12 || !lang.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                return Err(DownloaderError::InvalidInput(format!("Unsupported subtitle language {:?}", lang)));
+            }
+            if !subtitles.iter().any(|l| l == lang) {
+                subtitles.push(lang.to_string());
+            }
+        }
+        if subtitles.len() > MAX_SUBTITLE_TRACKS {
+            return Err(DownloaderError::InvalidInput(format!("At most {} subtitle languages are supported", MAX_SUBTITLE_TRACKS)));
+        }
+
+        return Ok(VideoOptions { container, codec, audio_codec, crf, preset, height, fps, max_filesize, clip: self.clip()?, subtitles });
+    }
+}
+fn parse_flag(name: &str, raw: Option<&str>) -> Result<Option<bool>, DownloaderError> {
+    return match raw.map(|x| x.to_ascii_lowercase()).as_deref() {
+        Some("true" | "1" | "yes" | "on") => Ok(Some(true)),
+        Some("false" | "0" | "no" | "off") => Ok(Some(false)),
+        Some(other) => Err(DownloaderError::InvalidInput(format!("Unsupported {} {:?}, use true or false", name, other))),
+        None => Ok(None),
+    }
+}
+#[derive(Debug, Deserialize)]
+pub struct SourceParams {
+    url: String
+}
+#[derive(Debug, Deserialize)]
+pub struct InfoParams {
+    /// Also list caption languages, which costs an extra yt-dlp run.
+    subtitles: Option<String>
+}
+#[derive(Debug, Deserialize)]
+pub struct WaveformParams {
+    points: Option<usize>
+}
+#[derive(Debug, Deserialize)]
+pub struct ThumbnailParams {
+    size: Option<u32>,
+    square: Option<String>,
+    format: Option<String>
+}
+
+impl ThumbnailParams {
+    fn thumbnail_options(&self) -> Result<ThumbnailOptions, DownloaderError> {
+        let size = match self.size {
+            Some(size) if size == 0 || size > thumbnails::MAX_SIZE => {
+                return Err(DownloaderError::InvalidInput(format!("size must be between 1 and {}", thumbnails::MAX_SIZE)));
+            },
+            size => size,
+        };
+        let format = match self.format.as_deref() {
+            Some(raw) => ThumbnailFormat::parse(raw)
+                .ok_or_else(|| DownloaderError::InvalidInput(format!("Unsupported format {:?}, use jpeg or webp", raw)))?,
+            None => ThumbnailFormat::default(),
+        };
+        let square = parse_flag("square", self.square.as_deref())?.unwrap_or(false);
+
+        return Ok(ThumbnailOptions { size, square, format });
+    }
+}
+#[derive(Debug, Deserialize)]
+pub struct SubtitleParams {
+    lang: Option<String>,
+    auto: Option<String>,
     format: Option<String>
 }
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    q: String,
+    limit: Option<usize>
+}
+#[derive(Debug, Deserialize)]
+pub struct JobRequest {
+    id: Option<String>,
+    url: Option<String>,
+    #[serde(flatten)]
+    params: DownloaderParams
+}
 #[derive(Deserialize, Debug)]
 struct Configuration {
     #[serde(default="default_max_video_duration_minutes")]
@@ -22,6 +289,39 @@ struct Configuration {
     limit_duration: bool,
     #[serde(default="default_max_audio_duration_minutes")]
     max_audio_duration_minutes: u16,
+    #[serde(default="default_max_video_filesize_mb")]
+    max_video_filesize_mb: u64,
+    #[serde(default="default_max_audio_bitrate")]
+    max_audio_bitrate: u16,
+    #[serde(default="default_normalize_audio")]
+    normalize_audio: bool,
+    #[serde(default="default_loudnorm_target_lufs")]
+    loudnorm_target_lufs: f64,
+    #[serde(default="default_loudnorm_true_peak")]
+    loudnorm_true_peak: f64,
+    #[serde(default="default_loudnorm_lra")]
+    loudnorm_lra: f64,
+    #[serde(default="default_replaygain")]
+    replaygain: bool,
+    #[serde(default="default_trim_silence")]
+    trim_silence: bool,
+    #[serde(default="default_silence_threshold_db")]
+    silence_threshold_db: f64,
+    #[serde(default="default_silence_min_duration")]
+    silence_min_duration: f64,
+    #[serde(default="default_stream_proxy")]
+    stream_proxy: bool,
+    #[serde(default="default_max_playlist_items")]
+    max_playlist_items: u16,
+    #[serde(default="default_max_playlist_size_mb")]
+    max_playlist_size_mb: u64,
+    #[serde(default="default_max_search_results")]
+    max_search_results: usize,
+    #[serde(default="default_max_concurrent_jobs")]
+    max_concurrent_jobs: usize,
+    #[serde(default="default_allowed_hosts")]
+    allowed_hosts: Vec<String>,
+    ffmpeg_path: Option<String>,
     #[serde(default="default_port")]
     port: u16
 }
@@ -34,599 +334,5475 @@ fn default_port() -> u16 { 3000 }
 
 fn default_max_audio_duration_minutes() -> u16 { 600 }
 
+fn default_max_audio_bitrate() -> u16 { 320 }
+
+fn default_max_video_filesize_mb() -> u64 { 1024 }
+
+fn default_normalize_audio() -> bool { false }
+
+fn default_loudnorm_target_lufs() -> f64 { -14.0 }
+
+fn default_loudnorm_true_peak() -> f64 { -1.0 }
+
+fn default_loudnorm_lra() -> f64 { 11.0 }
+
+fn default_replaygain() -> bool { false }
+
+fn default_trim_silence() -> bool { false }
+
+fn default_silence_threshold_db() -> f64 { -50.0 }
+
+fn default_silence_min_duration() -> f64 { 1.0 }
+
+fn default_stream_proxy() -> bool { false }
+
+fn default_max_playlist_items() -> u16 { 50 }
+
+fn default_max_playlist_size_mb() -> u64 { 4096 }
+
+fn default_max_search_results() -> usize { 20 }
+
+fn default_max_concurrent_jobs() -> usize { 2 }
+
+fn default_allowed_hosts() -> Vec<String> {
+    ["youtube.com", "youtu.be", "soundcloud.com", "bandcamp.com", "vimeo.com"]
+        .iter().map(|x| x.to_string()).collect()
+}
+
 #[get("/download_id/{id}")]
 async fn get_download_id(req: HttpRequest, path: web::Path<String>) -> HttpResponse { 
-    let id = path.into_inner();
-    let params = web::Query::<DownloaderParams>::from_query(req.query_string()).unwrap();
-    let url = format!("https://www.youtube.com/watch?v={}", id);
+    let link = match parse_link(&path.into_inner()) {
+        Some(link) => link,
+        None => return error_response(&req, DownloaderError::InvalidId("Invalid video id".to_string())),
+    };
+    let id = link.id;
+    let mut params = match web::Query::<DownloaderParams>::from_query(req.query_string()) {
+        Ok(params) => params.into_inner(),
+        Err(e) => return error_response(&req, DownloaderError::InvalidInput(e.to_string())),
+    };
+    params.apply_link_start(link.start);
 
-    let format = params.format.as_deref().unwrap_or("mp3");
+    let format = match params.media_format() {
+        Ok(format) => format,
+        Err(e) => return error_response(&req, e),
+    };
+    let options = match params.audio_options() {
+        Ok(options) => options,
+        Err(e) => return error_response(&req, e),
+    };
+    let video = match params.video_options() {
+        Ok(video) => video,
+        Err(e) => return error_response(&req, e),
+    };
 
-    if format == "mp4" {
-        match dl_get_video(&url, true).await {
-            Ok(pbf) => {
-                let f = af::NamedFile::open_async(&pbf).await.unwrap();
-                let hvalue = format!("attachment; filename={:?}", pbf.file_name().unwrap());
-                let a = f.into_response(&req);
-                return HttpResponse::Ok().append_header(("Content-Disposition", hvalue)).content_type("video/mp4").body(a.into_body());
-            },
-            Err(e) => {
-                return HttpResponse::from_error(io::Error::new(io::ErrorKind::InvalidInput, e.to_string()));
-            },
-        }
-    } else {
-        match dl_get_audio(&url).await {
-            Ok(pbf) => {
-                let f = af::NamedFile::open_async(&pbf).await.unwrap();
-                
-                let hvalue = format!("attachment; filename={:?}", pbf.file_name().unwrap());
-    
-                let a = f.into_response(&req);
-                println!("{}: {}", "Content-Disposition", &hvalue);
-                return HttpResponse::Ok().append_header(("Content-Disposition", hvalue)).content_type("audio/mpeg").body(a.into_body());
-            },
-            Err(e) => {
-                return HttpResponse::from_error(io::Error::new(io::ErrorKind::InvalidInput, e.to_string()));
-            },
-        }
+    let res = match format {
+        MediaFormat::Video(_) => dl_get_video(&id, true, &video).await,
+        MediaFormat::Audio(_) if options.split_chapters => dl_get_chapters(&id.watch_url(), &options).await,
+        MediaFormat::Audio(_) => dl_get_audio(&id, &options).await,
+    };
+
+    return match res {
+        Ok(pbf) => attachment(&req, &pbf, output_type(format, &options)).await,
+        Err(e) => error_response(&req, e),
     }
 }
 #[get("/stream_id/{id}")]
-async fn get_stream_id(req: HttpRequest, path: web::Path<String>) -> HttpResponse {
-    let id = path.into_inner();
-    let params = web::Query::<DownloaderParams>::from_query(req.query_string()).unwrap();
-    let url = format!("https://www.youtube.com/watch?v={}", id);
+async fn get_stream_id(req: HttpRequest, path: web::Path<String>, streams: web::Data<StreamCache>) -> HttpResponse {
+    let id = match parse_link(&path.into_inner()) {
+        Some(link) => link.id,
+        None => return error_response(&req, DownloaderError::InvalidId("Invalid video id".to_string())),
+    };
+    let params = match web::Query::<DownloaderParams>::from_query(req.query_string()) {
+        Ok(params) => params,
+        Err(e) => return error_response(&req, DownloaderError::InvalidInput(e.to_string())),
+    };
 
     let format = params.format.as_deref().unwrap_or("mp3");
 
-    if format == "mp4" {
+    if let Some(MediaFormat::Video(_)) = MediaFormat::parse(format) {
        return HttpResponse::MethodNotAllowed().body("Video streaming is not supported.");
-    } else {
-        return match get_audio(&url).await {
+    }
+
+    let c : Configuration = envy::from_env::<Configuration>().expect("Provide config.");
+    let proxy = match parse_flag("proxy", params.proxy.as_deref()) {
+        Ok(proxy) => proxy.unwrap_or(c.stream_proxy),
+        Err(e) => return error_response(&req, e),
+    };
+
+    if !proxy {
+        return match get_audio(&id).await {
             Ok(uri) => {
                 HttpResponse::Found().append_header(("Location", uri)).finish()
             },
             Err(e) => {
-                return HttpResponse::from_error(io::Error::new(io::ErrorKind::InvalidInput, e.to_string()));
+                return error_response(&req, e);
             },
         }
     }
+
+    let range = req.headers().get(header::RANGE).and_then(|r| r.to_str().ok());
+    let (resp, content_type) = match streams.open(&id, range).await {
+        Ok(opened) => opened,
+        Err(e) => return error_response(&req, e),
+    };
+
+    let status = StatusCode::from_u16(resp.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut builder = HttpResponse::build(status);
+    builder.content_type(content_type).insert_header((header::ACCEPT_RANGES, "bytes"));
+    if let Some(content_range) = resp.headers().get(reqwest::header::CONTENT_RANGE).and_then(|v| v.to_str().ok()) {
+        builder.insert_header((header::CONTENT_RANGE, content_range.to_string()));
+    }
+
+    // A sized body keeps Content-Length, which players need to seek.
+    return match resp.content_length() {
+        Some(length) => builder.body(SizedStream::new(length, streaming::body(resp))),
+        None => builder.streaming(streaming::body(resp)),
+    }
+}
+
+#[get("/download")]
+async fn get_download_url(req: HttpRequest) -> HttpResponse {
+    let source = match web::Query::<SourceParams>::from_query(req.query_string()) {
+        Ok(source) => source.into_inner(),
+        Err(e) => return error_response(&req, DownloaderError::InvalidInput(e.to_string())),
+    };
+    let mut params = match web::Query::<DownloaderParams>::from_query(req.query_string()) {
+        Ok(params) => params.into_inner(),
+        Err(e) => return error_response(&req, DownloaderError::InvalidInput(e.to_string())),
+    };
+    params.apply_link_start(parse_link(&source.url).and_then(|l| l.start));
+
+    let c : Configuration = envy::from_env::<Configuration>().expect("Provide config.");
+    if !is_allowed_url(&source.url, &c.allowed_hosts) {
+        return error_response(&req, DownloaderError::HostNotAllowed(source.url));
+    }
+
+    let format = match params.media_format() {
+        Ok(format) => format,
+        Err(e) => return error_response(&req, e),
+    };
+    let options = match params.audio_options() {
+        Ok(options) => options,
+        Err(e) => return error_response(&req, e),
+    };
+    let video = match params.video_options() {
+        Ok(video) => video,
+        Err(e) => return error_response(&req, e),
+    };
+
+    let res = match format {
+        MediaFormat::Video(_) => dl_get_video_url(&source.url, true, &video).await,
+        MediaFormat::Audio(_) if options.split_chapters => dl_get_chapters(&source.url, &options).await,
+        MediaFormat::Audio(_) => dl_get_audio_url(&source.url, &options).await,
+    };
+
+    return match res {
+        Ok(pbf) => attachment(&req, &pbf, output_type(format, &options)).await,
+        Err(e) => error_response(&req, e),
+    }
+}
+
+#[get("/download_playlist/{list_id}")]
+async fn get_download_playlist(req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+    let list = match PlaylistId::parse(&path.into_inner()) {
+        Some(list) => list,
+        None => return error_response(&req, DownloaderError::InvalidId("Invalid playlist id".to_string())),
+    };
+    let params = match web::Query::<DownloaderParams>::from_query(req.query_string()) {
+        Ok(params) => params,
+        Err(e) => return error_response(&req, DownloaderError::InvalidInput(e.to_string())),
+    };
+    if params.start.is_some() || params.end.is_some() {
+        return error_response(&req, DownloaderError::InvalidInput("start and end are not supported for playlists".to_string()));
+    }
+    if params.split.is_some() {
+        return error_response(&req, DownloaderError::InvalidInput("split is not supported for playlists".to_string()));
+    }
+
+    let format = match params.media_format() {
+        Ok(format) => format,
+        Err(e) => return error_response(&req, e),
+    };
+    let options = match params.audio_options() {
+        Ok(options) => options,
+        Err(e) => return error_response(&req, e),
+    };
+    let video = match params.video_options() {
+        Ok(video) => video,
+        Err(e) => return error_response(&req, e),
+    };
+
+    return match dl_get_playlist(&list, format, &options, &video).await {
+        Ok(pbf) => attachment(&req, &pbf, "application/zip").await,
+        Err(e) => error_response(&req, e),
+    }
+}
+
+/// audiowaveform-style peaks of the audio `/download_id` would return for the same query.
+#[get("/waveform_id/{id}")]
+async fn get_waveform_id(req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+    let id = match parse_link(&path.into_inner()) {
+        Some(link) => link.id,
+        None => return error_response(&req, DownloaderError::InvalidId("Invalid video id".to_string())),
+    };
+    let params = match web::Query::<DownloaderParams>::from_query(req.query_string()) {
+        Ok(params) => params,
+        Err(e) => return error_response(&req, DownloaderError::InvalidInput(e.to_string())),
+    };
+    let points = match web::Query::<WaveformParams>::from_query(req.query_string()) {
+        Ok(p) => p.points.unwrap_or(waveform::DEFAULT_POINTS),
+        Err(e) => return error_response(&req, DownloaderError::InvalidInput(e.to_string())),
+    };
+    if points == 0 || points > waveform::MAX_POINTS {
+        return error_response(&req, DownloaderError::InvalidInput(format!("points must be between 1 and {}", waveform::MAX_POINTS)));
+    }
+    let options = match params.audio_options() {
+        Ok(options) => options,
+        Err(e) => return error_response(&req, e),
+    };
+
+    let res = match (dl_get_audio(&id, &options).await, transcoder::ffmpeg()) {
+        (Ok(pbf), Ok(ffmpeg)) => waveform::waveform(ffmpeg, &pbf, points).await,
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    };
+
+    return match res {
+        Ok(waveform) => HttpResponse::Ok().json(waveform),
+        Err(e) => error_response(&req, e),
+    }
+}
+
+/// The video's thumbnail served from here, so clients never talk to YouTube directly.
+#[get("/thumbnail_id/{id}")]
+async fn get_thumbnail_id(req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+    let id = match parse_link(&path.into_inner()) {
+        Some(link) => link.id,
+        None => return error_response(&req, DownloaderError::InvalidId("Invalid video id".to_string())),
+    };
+    let options = match web::Query::<ThumbnailParams>::from_query(req.query_string()) {
+        Ok(params) => match params.thumbnail_options() {
+            Ok(options) => options,
+            Err(e) => return error_response(&req, e),
+        },
+        Err(e) => return error_response(&req, DownloaderError::InvalidInput(e.to_string())),
+    };
+
+    let pbf = match dl_get_thumbnail(&id, &options).await {
+        Ok(pbf) => pbf,
+        Err(e) => return error_response(&req, e),
+    };
+
+    return match tokio::fs::read(&pbf).await {
+        Ok(bytes) => HttpResponse::Ok()
+            .content_type(options.format.content_type())
+            .append_header(("Cache-Control", "public, max-age=86400"))
+            .body(bytes),
+        Err(e) => error_response(&req, DownloaderError::Io(e)),
+    }
 }
 
 #[get("/info_id/{id}")]
-async fn get_info_id(req: HttpRequest, path: web::Path<String>) -> web::Json<MediaMetadata> {
-    let id = path.into_inner();
-    let url = format!("https://www.youtube.com/watch?v={}", id);
+async fn get_info_id(req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+    let id = match parse_link(&path.into_inner()) {
+        Some(link) => link.id,
+        None => return error_response(&req, DownloaderError::InvalidId("Invalid video id".to_string())),
+    };
+
+    let subtitles = match web::Query::<InfoParams>::from_query(req.query_string()) {
+        Ok(params) => match parse_flag("subtitles", params.subtitles.as_deref()) {
+            Ok(subtitles) => subtitles.unwrap_or(false),
+            Err(e) => return error_response(&req, e),
+        },
+        Err(e) => return error_response(&req, DownloaderError::InvalidInput(e.to_string())),
+    };
 
-    let metadata = get_metadata_resp(&url).await.unwrap();
+    let metadata = if subtitles {
+        let (metadata, languages) = tokio::join!(get_metadata_resp(&id), get_subtitle_languages(&id));
+        metadata.map(|mut m| {
+            m.subtitles = languages;
+            m
+        })
+    } else {
+        get_metadata_resp(&id).await
+    };
 
-    return web::Json(metadata);
+    return match metadata {
+        Ok(metadata) => HttpResponse::Ok().json(metadata),
+        Err(e) => error_response(&req, e),
+    }
 }
-#[get("/html_info_id/{id}")]
-async fn html_get_info_id(req: HttpRequest, path: web::Path<String>) -> String {
-    use MediaMetadata;
+#[get("/subtitles_id/{id}")]
+async fn get_subtitles_id(req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+    let id = match parse_link(&path.into_inner()) {
+        Some(link) => link.id,
+        None => return error_response(&req, DownloaderError::InvalidId("Invalid video id".to_string())),
+    };
+    let params = match web::Query::<SubtitleParams>::from_query(req.query_string()) {
+        Ok(params) => params,
+        Err(e) => return error_response(&req, DownloaderError::InvalidInput(e.to_string())),
+    };
+    let format = match params.format.as_deref() {
+        Some(raw) => match SubtitleFormat::parse(raw) {
+            Some(format) => format,
+            None => return error_response(&req, DownloaderError::InvalidInput(format!("Unsupported format {:?}, use srt, vtt or txt", raw))),
+        },
+        None => SubtitleFormat::default(),
+    };
+    let auto = match parse_flag("auto", params.auto.as_deref()) {
+        Ok(auto) => auto.unwrap_or(false),
+        Err(e) => return error_response(&req, e),
+    };
+    let lang = params.lang.as_deref().filter(|l| !l.is_empty()).unwrap_or("en");
 
-    let id = path.into_inner();
-    let url = format!("https://www.youtube.com/watch?v={}", id);
+    return match dl_get_subtitles(&id, lang, auto, format).await {
+        Ok(pbf) => attachment(&req, &pbf, format.content_type()).await,
+        Err(e) => error_response(&req, e),
+    }
+}
+#[get("/html_info_id/{id}")]
+async fn html_get_info_id(req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+    let link = match parse_link(&path.into_inner()) {
+        Some(link) => link,
+        None => return error_response(&req, DownloaderError::InvalidId("Invalid video id".to_string())),
+    };
+    let id = link.id;
+    let url = id.watch_url();
 
-    let metadata_r = get_metadata_resp(&url).await;
+    let metadata = match get_metadata_resp(&id).await {
+        Ok(metadata) => metadata,
+        Err(e) => return error_response(&req, e),
+    };
 
-    let res = || -> Result<String, io::Error> {
-        let metadata = metadata_r.unwrap();
+    let th = format!("/thumbnail_id/{}?size=640", id);
 
-        let thumbnails = metadata.thumbnails.unwrap();
-        let th = thumbnails.iter().filter(|x| !x.url.contains("maxres")).max_by_key(|x| x.width).unwrap();
-    
-        let len = metadata.short_desc.len();
-        let mut dsc : String = metadata.short_desc.chars().take(300).collect();
-        
-        if len >= 300 {
-            dsc = format!("{}...", dsc);
-        }
+    let len = metadata.short_desc.len();
+    let mut dsc : String = metadata.short_desc.chars().take(300).collect();
     
-        let html = format!(r#"
-            <div class='col-12 align-items-center text-center justify-content-center'>
-                <img src='{}' style='object-fit: cover; width: 100%; user-select: none;' alt='thumbnail' class='img-fluid img-thumbnail'/>
-            </div>
-            <div class="col-12 align-items-center">
-                <h3><a href='{}'>{}</a></h3>
-                <p>{}</p>
-                <small>Author: {}</small>
-                <small>Length: {}s</small>
-            </div>
+    if len >= 300 {
+        dsc = format!("{}...", dsc);
+    }
+
+    let playlist = match link.list {
+        Some(list) => format!("<small><a href='/download_playlist/{}'>Download playlist</a></small>", list),
+        None => String::new(),
+    };
+
+    let html = format!(r#"
+        <div class='col-12 align-items-center text-center justify-content-center'>
+            <img src='{}' style='object-fit: cover; width: 100%; user-select: none;' alt='thumbnail' class='img-fluid img-thumbnail'/>
+        </div>
+        <div class="col-12 align-items-center">
+            <h3><a href='{}'>{}</a></h3>
+            <p>{}</p>
+            <small>Author: {}</small>
+            <small>Length: {}s</small>
+            {}
         </div>
-        "#, th.url, url, metadata.title, dsc, metadata.author, metadata.length);
+    </div>
+    "#, th, url, escape_html(&metadata.title), escape_html(&dsc), escape_html(&metadata.author), metadata.length, playlist);
+
+    return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(html);
+}
     
-        return Ok(html);
+#[get("/search")]
+async fn get_search(req: HttpRequest, params: web::Query<SearchParams>) -> HttpResponse {
+    let c : Configuration = envy::from_env::<Configuration>().expect("Provide config.");
+    let limit = params.limit.unwrap_or(5).min(c.max_search_results).max(1);
+
+    return match search_media(&params.q, limit).await {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(e) => error_response(&req, e),
+    }
+}
+#[get("/html_search")]
+async fn html_get_search(req: HttpRequest, params: web::Query<SearchParams>) -> HttpResponse {
+    let c : Configuration = envy::from_env::<Configuration>().expect("Provide config.");
+    let limit = params.limit.unwrap_or(5).min(c.max_search_results).max(1);
+
+    if params.q.trim().is_empty() {
+        return HttpResponse::Ok().content_type("text/html; charset=utf-8").finish();
+    }
+
+    let results = match search_media(&params.q, limit).await {
+        Ok(results) => results,
+        Err(e) => return error_response(&req, e),
     };
 
-    return res().unwrap_or("".to_string());
+    let mut html = String::new();
+
+    for metadata in results {
+        let th = format!("/thumbnail_id/{}?size=240", metadata.id);
+
+        html.push_str(&format!(r#"
+            <div class='col-12 d-flex align-items-center py-2 border-bottom'>
+                <img src='{}' style='object-fit: cover; width: 120px; aspect-ratio: 16/9; user-select: none;' alt='thumbnail' class='img-thumbnail me-3'/>
+                <div class='flex-grow-1'>
+                    <h6 class='mb-1'>{}</h6>
+                    <small>Author: {}</small>
+                    <small>Length: {}s</small>
+                </div>
+                <div class='btn-group btn-group-sm'>
+                    <button type='button' class='btn btn-primary' data-id='{}' data-action='download'>Download</button>
+                    <button type='button' class='btn btn-secondary' data-id='{}' data-action='stream'>Stream</button>
+                </div>
+            </div>
+        "#, escape_html(&th), escape_html(&metadata.title), escape_html(&metadata.author), metadata.length, metadata.id, metadata.id));
+    }
+
+    return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(html);
 }
-    
 
-#[actix_web::main]
-async fn main() -> io::Result<()> {
-    dotenv().ok();
-    let c : Configuration = envy::from_env::<Configuration>().expect("Provide all configuration variables.");
+#[post("/jobs")]
+async fn post_job(req: HttpRequest, jobs: web::Data<JobStore>, body: web::Either<web::Json<JobRequest>, web::Form<JobRequest>>) -> HttpResponse {
+    let mut job = body.into_inner();
+    let link_start = job.id.as_deref().or(job.url.as_deref()).and_then(parse_link).and_then(|l| l.start);
+    job.params.apply_link_start(link_start);
+    let format = match job.params.media_format() {
+        Ok(format) => format,
+        Err(e) => return error_response(&req, e),
+    };
+    let options = match job.params.audio_options() {
+        Ok(options) => options,
+        Err(e) => return error_response(&req, e),
+    };
+    let video = match job.params.video_options() {
+        Ok(video) => video,
+        Err(e) => return error_response(&req, e),
+    };
+    let content_type = output_type(format, &options);
 
-    let port = c.port;
+    let id = match (job.id.as_deref(), job.url) {
+        (Some(raw), _) => {
+            let id = match parse_link(raw) {
+                Some(link) => link.id,
+                None => return error_response(&req, DownloaderError::InvalidId("Invalid video id".to_string())),
+            };
+            jobs.into_inner().spawn(content_type, async move {
+                match format {
+                    MediaFormat::Video(_) => dl_get_video(&id, true, &video).await,
+                    MediaFormat::Audio(_) if options.split_chapters => dl_get_chapters(&id.watch_url(), &options).await,
+                    MediaFormat::Audio(_) => dl_get_audio(&id, &options).await,
+                }
+            })
+        },
+        (None, Some(url)) => {
+            let c : Configuration = envy::from_env::<Configuration>().expect("Provide config.");
+            if !is_allowed_url(&url, &c.allowed_hosts) {
+                return error_response(&req, DownloaderError::HostNotAllowed(url));
+            }
+            jobs.into_inner().spawn(content_type, async move {
+                match format {
+                    MediaFormat::Video(_) => dl_get_video_url(&url, true, &video).await,
+                    MediaFormat::Audio(_) if options.split_chapters => dl_get_chapters(&url, &options).await,
+                    MediaFormat::Audio(_) => dl_get_audio_url(&url, &options).await,
+                }
+            })
+        },
+        (None, None) => return error_response(&req, DownloaderError::InvalidInput("Provide either id or url".to_string())),
+    };
 
-    println!("Running on port {}", c.port);
-    let _root: PathBuf = env::current_dir().unwrap();
-    let tmp_path = _root.join("temp");
-    fs::remove_dir_all(&tmp_path)?;
-    fs::create_dir_all(&tmp_path)?;
-    
-    let ws = HttpServer::new(|| {
-        let cors = Cors::permissive();
-        App::new()
-            .wrap(cors)
-            .service(get_download_id)
-            .service(get_stream_id)
-            .service(get_info_id)
-            .service(html_get_info_id)
-            .service(af::Files::new("/", "./public")
-                .use_last_modified(true)
-                .index_file("index.html")
-            )
-    })
-    .bind(("127.0.0.1", port))?
-    .bind(("0.0.0.0", port))?
-    .run();
-    open::that(format!("http://localhost:{}", &port))?;
-    ws.await?;
+    return HttpResponse::Accepted()
+        .append_header(("Location", format!("/jobs/{}", id)))
+        .json(serde_json::json!({ "id": id, "status": format!("/jobs/{}", id), "file": format!("/jobs/{}/file", id) }));
+}
+#[get("/jobs/{job_id}")]
+async fn get_job(req: HttpRequest, jobs: web::Data<JobStore>, path: web::Path<String>) -> HttpResponse {
+    return match jobs.get(&path.into_inner()) {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => error_response(&req, DownloaderError::NotFound("Job not found".to_string())),
+    }
+}
+#[get("/jobs/{job_id}/file")]
+async fn get_job_file(req: HttpRequest, jobs: web::Data<JobStore>, path: web::Path<String>) -> HttpResponse {
+    let job = match jobs.get(&path.into_inner()) {
+        Some(job) => job,
+        None => return error_response(&req, DownloaderError::NotFound("Job not found".to_string())),
+    };
+
+    return match (job.state, job.path) {
+        (JobState::Done, Some(pbf)) => attachment(&req, &pbf, &job.content_type).await,
+        (JobState::Failed, _) => {
+            // The job exists, so its own 4xx (e.g. a missing video) would be misleading here.
+            let status = job.error_status.filter(|s| s.is_server_error()).unwrap_or(StatusCode::CONFLICT);
+            render_error(&req, status, &format!("Job failed: {}", job.error.unwrap_or_default()))
+        },
+        _ => error_response(&req, DownloaderError::NotReady("Job is not finished yet".to_string())),
+    }
+}
+
+/// Server-Sent Events stream of a job's progress. Closes after the job is done or failed.
+#[get("/jobs/{job_id}/events")]
+async fn get_job_events(req: HttpRequest, jobs: web::Data<JobStore>, path: web::Path<String>) -> HttpResponse {
+    let (job, rx) = match jobs.subscribe(&path.into_inner()) {
+        Some(sub) => sub,
+        None => return error_response(&req, DownloaderError::NotFound("Job not found".to_string())),
+    };
+    let first = job.progress.unwrap_or_else(|| ProgressEvent::new(job.state));
+
+    let stream = futures_util::stream::unfold((Some(first), rx, false), |(pending, mut rx, finished)| async move {
+        if finished {
+            return None;
+        }
+
+        let event = match pending {
+            Some(event) => event,
+            None => loop {
+                match rx.recv().await {
+                    Ok(event) => break event,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            },
+        };
+
+        let done = matches!(event.state, JobState::Done | JobState::Failed);
+        let chunk = format!("event: progress\ndata: {}\n\n", serde_json::to_string(&event).unwrap_or_default());
+
+        return Some((Ok::<_, actix_web::Error>(web::Bytes::from(chunk)), (None, rx, done)));
+    });
+
+    return HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .append_header(("Cache-Control", "no-cache"))
+        .streaming(stream);
+}
+
+fn escape_html(raw: &str) -> String {
+    return raw.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('\'', "&#39;").replace('"', "&quot;");
+}
+
+/// Content type of what a download with these options returns.
+fn output_type(format: MediaFormat, options: &AudioOptions) -> &'static str {
+    return match format {
+        MediaFormat::Audio(_) if options.split_chapters => "application/zip",
+        _ => format.content_type(),
+    }
+}
+
+/// Serves a finished file from the temp storage as a download.
+async fn attachment(req: &HttpRequest, pbf: &Path, content_type: &str) -> HttpResponse {
+    let f = match af::NamedFile::open_async(pbf).await {
+        Ok(f) => f,
+        Err(e) => return error_response(req, DownloaderError::Io(e)),
+    };
+    let hvalue = format!("attachment; filename={:?}", pbf.file_name().unwrap_or_default());
+    println!("Content-Disposition: {}", &hvalue);
+
+    let a = f.into_response(req);
+    return HttpResponse::Ok().append_header(("Content-Disposition", hvalue)).content_type(content_type).body(a.into_body());
+}
+
+impl ResponseError for DownloaderError {
+    fn status_code(&self) -> StatusCode {
+        return match self {
+            DownloaderError::InvalidId(_) | DownloaderError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            DownloaderError::HostNotAllowed(_) => StatusCode::FORBIDDEN,
+            DownloaderError::NotFound(_) => StatusCode::NOT_FOUND,
+            DownloaderError::NotReady(_) => StatusCode::CONFLICT,
+            DownloaderError::DurationExceeded(_) | DownloaderError::FileTooLarge(_) | DownloaderError::PlaylistTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            DownloaderError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            DownloaderError::Transcode(_) | DownloaderError::Tag(_) | DownloaderError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        return problem_response(self, None);
+    }
+}
+
+/// RFC 7807 problem details body.
+#[derive(Debug, Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    kind: String,
+    title: String,
+    status: u16,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+}
+
+/// Renders `err` as a problem+json body, or as an HTML fragment when the
+/// request comes from htmx so it can be swapped straight into the page.
+fn error_response(req: &HttpRequest, err: DownloaderError) -> HttpResponse {
+    println!("Error: {}", err);
+
+    render_error(req, err.status_code(), &err.to_string())
+}
+
+fn render_error(req: &HttpRequest, status: StatusCode, detail: &str) -> HttpResponse {
+    if req.headers().contains_key("HX-Request") {
+        let html = format!(r#"
+            <div class='col-12 alert alert-danger' role='alert'>
+                <strong>{}</strong> {}
+            </div>
+        "#, status.canonical_reason().unwrap_or("Error"), escape_html(detail));
+
+        return HttpResponse::build(status).content_type("text/html; charset=utf-8").body(html);
+    }
+
+    return problem(status, detail, Some(req.path().to_string()));
+}
+
+fn problem_response(err: &DownloaderError, instance: Option<String>) -> HttpResponse {
+    problem(err.status_code(), &err.to_string(), instance)
+}
+
+fn problem(status: StatusCode, detail: &str, instance: Option<String>) -> HttpResponse {
+    let problem = Problem {
+        kind: "about:blank".to_string(),
+        title: status.canonical_reason().unwrap_or("Error").to_string(),
+        status: status.as_u16(),
+        detail: detail.to_string(),
+        instance,
+    };
+
+    return HttpResponse::build(status).content_type("application/problem+json").json(problem);
+}
+
+#[actix_web::main]
+async fn main() -> io::Result<()> {
+    dotenv().ok();
+    let c : Configuration = envy::from_env::<Configuration>().expect("Provide all configuration variables.");
+
+    let port = c.port;
+
+    println!("Running on port {}", c.port);
+    let _root: PathBuf = env::current_dir().unwrap();
+    let tmp_path = _root.join("temp");
+    fs::remove_dir_all(&tmp_path)?;
+    fs::create_dir_all(&tmp_path)?;
+    
+    match transcoder::ffmpeg() {
+        Ok(ffmpeg) => println!("Using ffmpeg {} at {}", ffmpeg.version, ffmpeg.path.display()),
+        Err(e) => return Err(io::Error::other(e.to_string())),
+    }
+
+    let jobs = web::Data::new(JobStore::new(c.max_concurrent_jobs));
+    let streams = web::Data::new(StreamCache::new());
+
+    let ws = HttpServer::new(move || {
+        let cors = Cors::permissive();
+        App::new()
+            .wrap(cors)
+            .app_data(jobs.clone())
+            .app_data(streams.clone())
+            .service(get_download_id)
+            .service(get_stream_id)
+            .service(get_download_playlist)
+            .service(get_download_url)
+            .service(get_waveform_id)
+            .service(get_thumbnail_id)
+            .service(get_info_id)
+            .service(get_subtitles_id)
+            .service(html_get_info_id)
+            .service(get_search)
+            .service(html_get_search)
+            .service(post_job)
+            .service(get_job)
+            .service(get_job_file)
+            .service(get_job_events)
+            .service(af::Files::new("/", "./public")
+                .use_last_modified(true)
+                .index_file("index.html")
+            )
+    })
+    .bind(("127.0.0.1", port))?
+    .bind(("0.0.0.0", port))?
+    .run();
+    open::that(format!("http://localhost:{}", &port))?;
+    ws.await?;
+
+    return Ok(());
+}
+
+
+
+pub mod downloader {
+    use rustube::video_info::player_response::video_details::Thumbnail;
+    use youtube_dl::{YoutubeDl, SingleVideo, Playlist, SearchOptions};
+    use std::path::{Path, PathBuf};
+    use std::{fmt, io};
+    use std::fs;
+    use std::env;
+    use url::Url;
+    use std::process::Stdio;
+    use crate::progress;
+    use crate::transcoder::{Transcoder, TranscodeJob};
+    use crate::tagging::{TrackTags, fetch_cover, write_tags, write_replaygain};
+    use crate::loudness::{self, LoudnessMeasurement, LoudnessTarget, ReplayGain};
+    use crate::silence::{self, SilenceSettings, SilenceTrim};
+    use crate::thumbnails::{fetch_url, known_source, render, thumbnail_url, ThumbnailOptions};
+    use crate::subtitles::{self, Cue, SubtitleFormat, SubtitleLanguages, SubtitleTrack};
+    use crate::streaming::{self, StreamSource};
+    use rustube::*;
+
+    /// Everything that can go wrong between an incoming request and a finished file.
+    #[derive(Debug)]
+    pub enum DownloaderError {
+        InvalidId(String),
+        InvalidInput(String),
+        HostNotAllowed(String),
+        DurationExceeded(String),
+        FileTooLarge(String),
+        PlaylistTooLarge(u16),
+        NotFound(String),
+        NotReady(String),
+        Upstream(String),
+        Transcode(String),
+        Tag(String),
+        Io(io::Error),
+    }
+
+    impl fmt::Display for DownloaderError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            return match self {
+                DownloaderError::InvalidId(msg) => write!(f, "{}", msg),
+                DownloaderError::InvalidInput(msg) => write!(f, "{}", msg),
+                DownloaderError::HostNotAllowed(url) => write!(f, "Host of {} is not on the allowlist", url),
+                DownloaderError::DurationExceeded(msg) => write!(f, "{}", msg),
+                DownloaderError::FileTooLarge(msg) => write!(f, "{}", msg),
+                DownloaderError::PlaylistTooLarge(max) => write!(f, "Playlist exceeds maximum of {} items", max),
+                DownloaderError::NotFound(msg) => write!(f, "{}", msg),
+                DownloaderError::NotReady(msg) => write!(f, "{}", msg),
+                DownloaderError::Upstream(msg) => write!(f, "Upstream error: {}", msg),
+                DownloaderError::Transcode(msg) => write!(f, "Transcoding failed: {}", msg),
+                DownloaderError::Tag(msg) => write!(f, "Tagging failed: {}", msg),
+                DownloaderError::Io(e) => write!(f, "I/O error: {}", e),
+            }
+        }
+    }
+
+    impl std::error::Error for DownloaderError {}
+
+    impl From<io::Error> for DownloaderError {
+        fn from(e: io::Error) -> Self {
+            return DownloaderError::Io(e);
+        }
+    }
+
+    impl From<rustube::Error> for DownloaderError {
+        fn from(e: rustube::Error) -> Self {
+            return DownloaderError::Upstream(e.to_string());
+        }
+    }
+
+    /// Audio container/codec combinations `process_audio` can produce.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub enum AudioFormat {
+        #[default]
+        Mp3,
+        Flac,
+        M4a,
+        Ogg,
+        Opus,
+        Wav,
+    }
+
+    impl AudioFormat {
+        pub fn parse(raw: &str) -> Option<AudioFormat> {
+            return match raw.to_ascii_lowercase().as_str() {
+                "mp3" => Some(AudioFormat::Mp3),
+                "flac" => Some(AudioFormat::Flac),
+                "m4a" | "aac" => Some(AudioFormat::M4a),
+                "ogg" | "vorbis" => Some(AudioFormat::Ogg),
+                "opus" => Some(AudioFormat::Opus),
+                "wav" => Some(AudioFormat::Wav),
+                _ => None,
+            }
+        }
+
+        pub fn extension(&self) -> &'static str {
+            return match self {
+                AudioFormat::Mp3 => "mp3",
+                AudioFormat::Flac => "flac",
+                AudioFormat::M4a => "m4a",
+                AudioFormat::Ogg => "ogg",
+                AudioFormat::Opus => "opus",
+                AudioFormat::Wav => "wav",
+            }
+        }
+
+        pub fn content_type(&self) -> &'static str {
+            return match self {
+                AudioFormat::Mp3 => "audio/mpeg",
+                AudioFormat::Flac => "audio/flac",
+                AudioFormat::M4a => "audio/mp4",
+                AudioFormat::Ogg => "audio/ogg",
+                AudioFormat::Opus => "audio/ogg; codecs=opus",
+                AudioFormat::Wav => "audio/wav",
+            }
+        }
+
+        /// ffmpeg output arguments selecting the codec for this format.
+        pub fn ffmpeg_args(&self) -> Vec<&'static str> {
+            return match self {
+                AudioFormat::Mp3 => vec!["-vn", "-c:a", "libmp3lame"],
+                AudioFormat::Flac => vec!["-vn", "-c:a", "flac"],
+                AudioFormat::M4a => vec!["-vn", "-c:a", "aac", "-movflags", "+faststart"],
+                AudioFormat::Ogg => vec!["-vn", "-c:a", "libvorbis"],
+                AudioFormat::Opus => vec!["-vn", "-c:a", "libopus"],
+                AudioFormat::Wav => vec!["-vn", "-c:a", "pcm_s16le"],
+            }
+        }
+
+        /// yt-dlp format selector, preferring a source stream we can pass through untouched.
+        pub fn ytdlp_selector(&self) -> &'static str {
+            return match self {
+                AudioFormat::Opus => "bestaudio[acodec=opus]/bestaudio",
+                AudioFormat::M4a => "bestaudio[acodec^=mp4a]/bestaudio",
+                _ => "bestaudio",
+            }
+        }
+
+        /// Whether a source stream with yt-dlp's `acodec` can go into this format as is.
+        pub fn matches_codec(&self, acodec: &str) -> bool {
+            let acodec = acodec.to_ascii_lowercase();
+
+            return match self {
+                AudioFormat::Opus => acodec == "opus",
+                AudioFormat::M4a => acodec.starts_with("mp4a") || acodec == "aac",
+                AudioFormat::Mp3 => acodec == "mp3",
+                AudioFormat::Flac => acodec == "flac",
+                AudioFormat::Ogg => acodec == "vorbis",
+                AudioFormat::Wav => false,
+            }
+        }
+
+        pub fn is_lossless(&self) -> bool {
+            return matches!(self, AudioFormat::Flac | AudioFormat::Wav);
+        }
+
+        /// Bitrate in kbps used when the request doesn't pick one. Vorbis and the
+        /// lossless formats are quality based instead.
+        pub fn default_bitrate(&self) -> Option<u16> {
+            return match self {
+                AudioFormat::Mp3 => Some(320),
+                AudioFormat::M4a => Some(256),
+                AudioFormat::Opus => Some(160),
+                AudioFormat::Ogg | AudioFormat::Flac | AudioFormat::Wav => None,
+            }
+        }
+    }
+
+    /// Bitrates accepted by `?bitrate=`, in kbps.
+    pub const AUDIO_BITRATES: [u16; 5] = [96, 128, 192, 256, 320];
+
+    /// Average bitrate of LAME's V0..V9 presets, used for encoders without a matching VBR scale.
+    const VBR_KBPS: [u16; 10] = [245, 225, 190, 175, 165, 130, 115, 100, 85, 65];
+
+    /// Output settings for `dl_get_audio`.
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct AudioOptions {
+        pub format: AudioFormat,
+        /// Constant bitrate in kbps, one of `AUDIO_BITRATES`.
+        pub bitrate: Option<u16>,
+        /// VBR preset, 0 (best) to 9 (smallest), like LAME's `-V`.
+        pub quality: Option<u8>,
+        /// Two-pass EBU R128 normalization to this target.
+        pub normalize: Option<LoudnessTarget>,
+        /// Tag ReplayGain 2.0 values instead of touching the audio.
+        pub replaygain: bool,
+        /// Cut silent intros and outros.
+        pub trim_silence: Option<SilenceSettings>,
+        /// Return one track per chapter as ZIP instead of a single file.
+        pub split_chapters: bool,
+        pub clip: Option<Clip>,
+    }
+
+    impl AudioOptions {
+        /// Approximate bitrate in kbps the encoder will produce, `None` for lossless formats.
+        pub fn effective_bitrate(&self) -> Option<u16> {
+            if self.format.is_lossless() {
+                return None;
+            }
+
+            match (self.bitrate, self.quality) {
+                (Some(bitrate), _) => Some(bitrate),
+                (None, Some(quality)) => Some(VBR_KBPS[quality as usize]),
+                // Vorbis defaults to -q:a 8, the same as v1.
+                (None, None) => Some(self.format.default_bitrate().unwrap_or(VBR_KBPS[1])),
+            }
+        }
+
+        /// Rejects an explicit bitrate or quality above `max` kbps and lowers the
+        /// format's default to the highest allowed bitrate instead.
+        pub fn cap_bitrate(mut self, max: u16) -> Result<AudioOptions, DownloaderError> {
+            match self.effective_bitrate() {
+                Some(effective) if effective > max => {
+                    let exceeded = DownloaderError::InvalidInput(format!("Bitrate exceeds maximum of {}k", max));
+                    if self.bitrate.is_some() || self.quality.is_some() {
+                        return Err(exceeded);
+                    }
+                    self.bitrate = Some(AUDIO_BITRATES.iter().rev().copied().find(|b| *b <= max).ok_or(exceeded)?);
+                    Ok(self)
+                },
+                _ => Ok(self),
+            }
+        }
+
+        /// Codec and rate control arguments for ffmpeg.
+        pub fn ffmpeg_args(&self) -> Vec<String> {
+            let mut args: Vec<String> = self.format.ffmpeg_args().iter().map(|x| x.to_string()).collect();
+
+            if self.format.is_lossless() {
+                return args;
+            }
+
+            match (self.bitrate, self.quality) {
+                (Some(bitrate), _) => {
+                    args.extend(["-b:a".to_string(), format!("{}k", bitrate)]);
+                },
+                (None, Some(quality)) => {
+                    match self.format {
+                        AudioFormat::Mp3 => args.extend(["-q:a".to_string(), quality.to_string()]),
+                        AudioFormat::Ogg => args.extend(["-q:a".to_string(), (9 - quality).to_string()]),
+                        _ => args.extend(["-b:a".to_string(), format!("{}k", VBR_KBPS[quality as usize])]),
+                    }
+                },
+                (None, None) => {
+                    match self.format.default_bitrate() {
+                        Some(bitrate) => args.extend(["-b:a".to_string(), format!("{}k", bitrate)]),
+                        None => args.extend(["-q:a".to_string(), "8".to_string()]),
+                    }
+                },
+            }
+
+            return args;
+        }
+
+        /// Stream copy arguments when the source codec already fits the target
+        /// and no re-encode was asked for, `None` when we have to transcode.
+        /// Trimmed output is always re-encoded, a copy can only cut on packet boundaries.
+        pub fn passthrough_args(&self, source_codec: Option<&str>, trim: Option<&Clip>) -> Option<Vec<String>> {
+            if self.bitrate.is_some() || self.quality.is_some() || self.normalize.is_some() {
+                return None;
+            }
+            if trim.is_some() || self.trim_silence.is_some() {
+                return None;
+            }
+            if !source_codec.is_some_and(|c| self.format.matches_codec(c)) {
+                return None;
+            }
+
+            let mut args: Vec<String> = vec!["-vn".to_string(), "-c:a".to_string(), "copy".to_string()];
+            if self.format == AudioFormat::M4a {
+                args.extend(["-movflags".to_string(), "+faststart".to_string()]);
+            }
+
+            return Some(args);
+        }
+
+        /// Suffix that keeps differently encoded copies apart in the cache.
+        /// Empty for the format's default settings so existing files stay valid.
+        pub fn cache_tag(&self) -> String {
+            let norm = match &self.normalize {
+                Some(target) => target.cache_tag(),
+                None => "".to_string(),
+            };
+            let silence = self.trim_silence.map(|s| s.cache_tag()).unwrap_or_default();
+            if self.format.is_lossless() {
+                return format!("{}{}{}", norm, silence, self.clip.map(|c| c.cache_tag()).unwrap_or_default());
+            }
+
+            let rate = match (self.bitrate, self.quality) {
+                (Some(bitrate), _) if Some(bitrate) != self.format.default_bitrate() => format!(" {}k", bitrate),
+                (None, Some(quality)) => format!(" v{}", quality),
+                _ => "".to_string(),
+            };
+
+            let clip = self.clip.map(|c| c.cache_tag()).unwrap_or_default();
+
+            return format!("{}{}{}{}", rate, norm, silence, clip);
+        }
+    }
+
+    /// What a download request should produce.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum MediaFormat {
+        Video(VideoContainer),
+        Audio(AudioFormat),
+    }
+
+    impl MediaFormat {
+        pub fn parse(raw: &str) -> Option<MediaFormat> {
+            if let Some(container) = VideoContainer::parse(raw) {
+                return Some(MediaFormat::Video(container));
+            }
+
+            return AudioFormat::parse(raw).map(MediaFormat::Audio);
+        }
+
+        pub fn extension(&self) -> &'static str {
+            return match self {
+                MediaFormat::Video(c) => c.extension(),
+                MediaFormat::Audio(f) => f.extension(),
+            }
+        }
+
+        pub fn content_type(&self) -> &'static str {
+            return match self {
+                MediaFormat::Video(c) => c.content_type(),
+                MediaFormat::Audio(f) => f.content_type(),
+            }
+        }
+    }
+
+    /// Video containers `process_video` can produce.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub enum VideoContainer {
+        #[default]
+        Mp4,
+        Mkv,
+        Webm,
+    }
+
+    impl VideoContainer {
+        pub fn parse(raw: &str) -> Option<VideoContainer> {
+            return match raw.to_ascii_lowercase().as_str() {
+                "mp4" => Some(VideoContainer::Mp4),
+                "mkv" | "matroska" => Some(VideoContainer::Mkv),
+                "webm" => Some(VideoContainer::Webm),
+                _ => None,
+            }
+        }
+
+        pub fn extension(&self) -> &'static str {
+            return match self {
+                VideoContainer::Mp4 => "mp4",
+                VideoContainer::Mkv => "mkv",
+                VideoContainer::Webm => "webm",
+            }
+        }
+
+        pub fn content_type(&self) -> &'static str {
+            return match self {
+                VideoContainer::Mp4 => "video/mp4",
+                VideoContainer::Mkv => "video/x-matroska",
+                VideoContainer::Webm => "video/webm",
+            }
+        }
+
+        /// Text subtitle codec the container can carry.
+        pub fn subtitle_codec(&self) -> &'static str {
+            return match self {
+                VideoContainer::Mp4 => "mov_text",
+                VideoContainer::Mkv => "srt",
+                VideoContainer::Webm => "webvtt",
+            }
+        }
+
+        pub fn default_codec(&self) -> VideoCodec {
+            return match self {
+                VideoContainer::Webm => VideoCodec::Vp9,
+                _ => VideoCodec::H264,
+            }
+        }
+
+        pub fn default_audio_codec(&self) -> VideoAudioCodec {
+            return match self {
+                VideoContainer::Webm => VideoAudioCodec::Opus,
+                _ => VideoAudioCodec::Aac,
+            }
+        }
+
+        /// Whether a video stream with yt-dlp's `vcodec` can be copied into this container.
+        pub fn allows_video(&self, vcodec: &str) -> bool {
+            let vcodec = vcodec.to_ascii_lowercase();
+            let family = ["avc1", "h264", "hev1", "hvc1", "h265", "vp9", "vp09", "av01", "av1"].iter()
+                .find(|f| vcodec.starts_with(*f));
+
+            return match (self, family) {
+                (_, None) => false,
+                (VideoContainer::Mkv, Some(_)) => true,
+                (VideoContainer::Mp4, Some(f)) => !f.starts_with("vp"),
+                (VideoContainer::Webm, Some(f)) => f.starts_with("vp") || f.starts_with("av0") || *f == "av1",
+            }
+        }
+
+        /// Whether an audio stream with yt-dlp's `acodec` can be copied into this container.
+        pub fn allows_audio(&self, acodec: &str) -> bool {
+            let acodec = acodec.to_ascii_lowercase();
+
+            return match self {
+                VideoContainer::Mkv => ["mp4a", "aac", "opus", "vorbis", "mp3", "flac"].iter().any(|c| acodec.starts_with(c)),
+                VideoContainer::Mp4 => ["mp4a", "aac", "mp3"].iter().any(|c| acodec.starts_with(c)),
+                VideoContainer::Webm => acodec == "opus" || acodec == "vorbis",
+            }
+        }
+    }
+
+    /// Video encoders `process_video` knows how to drive.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum VideoCodec {
+        H264,
+        H265,
+        Vp9,
+        /// AV1 through libaom, slow but widely available.
+        Av1Aom,
+        /// AV1 through SVT-AV1, much faster at similar quality.
+        Av1Svt,
+    }
+
+    pub const X26X_PRESETS: [&str; 9] = ["ultrafast", "superfast", "veryfast", "faster", "fast", "medium", "slow", "slower", "veryslow"];
+
+    impl VideoCodec {
+        pub fn parse(raw: &str) -> Option<VideoCodec> {
+            return match raw.to_ascii_lowercase().as_str() {
+                "h264" | "avc" | "x264" => Some(VideoCodec::H264),
+                "h265" | "hevc" | "x265" => Some(VideoCodec::H265),
+                "vp9" => Some(VideoCodec::Vp9),
+                "av1" | "libaom" | "av1-aom" => Some(VideoCodec::Av1Aom),
+                "svtav1" | "svt-av1" | "av1-svt" => Some(VideoCodec::Av1Svt),
+                _ => None,
+            }
+        }
+
+        pub fn encoder(&self) -> &'static str {
+            return match self {
+                VideoCodec::H264 => "libx264",
+                VideoCodec::H265 => "libx265",
+                VideoCodec::Vp9 => "libvpx-vp9",
+                VideoCodec::Av1Aom => "libaom-av1",
+                VideoCodec::Av1Svt => "libsvtav1",
+            }
+        }
+
+        /// Prefix yt-dlp reports in `vcodec` for streams of this codec.
+        pub fn source_name(&self) -> &'static str {
+            return match self {
+                VideoCodec::H264 => "avc1",
+                VideoCodec::H265 => "hev1",
+                VideoCodec::Vp9 => "vp09",
+                VideoCodec::Av1Aom | VideoCodec::Av1Svt => "av01",
+            }
+        }
+
+        pub fn matches(&self, vcodec: &str) -> bool {
+            let vcodec = vcodec.to_ascii_lowercase();
+
+            return match self {
+                VideoCodec::H264 => vcodec.starts_with("avc1") || vcodec.starts_with("h264"),
+                VideoCodec::H265 => vcodec.starts_with("hev1") || vcodec.starts_with("hvc1") || vcodec.starts_with("h265"),
+                VideoCodec::Vp9 => vcodec.starts_with("vp9") || vcodec.starts_with("vp09"),
+                VideoCodec::Av1Aom | VideoCodec::Av1Svt => vcodec.starts_with("av01") || vcodec.starts_with("av1"),
+            }
+        }
+
+        /// Highest `-crf` the encoder accepts.
+        pub fn max_crf(&self) -> u8 {
+            match self {
+                VideoCodec::H264 | VideoCodec::H265 => 51,
+                VideoCodec::Vp9 | VideoCodec::Av1Aom | VideoCodec::Av1Svt => 63,
+            }
+        }
+
+        pub fn default_crf(&self) -> u8 {
+            return match self {
+                VideoCodec::H264 => 26,
+                VideoCodec::H265 => 28,
+                VideoCodec::Vp9 | VideoCodec::Av1Aom => 32,
+                VideoCodec::Av1Svt => 35,
+            }
+        }
+
+        /// Speed presets: x264/x265 names, `-cpu-used` 0..=8 for libvpx/libaom, 0..=13 for SVT-AV1.
+        pub fn parse_preset(&self, raw: &str) -> Option<String> {
+            let raw = raw.to_ascii_lowercase();
+
+            return match self {
+                VideoCodec::H264 | VideoCodec::H265 => X26X_PRESETS.contains(&raw.as_str()).then_some(raw),
+                VideoCodec::Vp9 | VideoCodec::Av1Aom => raw.parse::<u8>().ok().filter(|p| *p <= 8).map(|p| p.to_string()),
+                VideoCodec::Av1Svt => raw.parse::<u8>().ok().filter(|p| *p <= 13).map(|p| p.to_string()),
+            }
+        }
+
+        pub fn default_preset(&self) -> &'static str {
+            return match self {
+                VideoCodec::H264 | VideoCodec::H265 => "fast",
+                VideoCodec::Vp9 => "4",
+                VideoCodec::Av1Aom => "6",
+                VideoCodec::Av1Svt => "8",
+            }
+        }
+
+        /// Encoder, quality and speed arguments for ffmpeg.
+        pub fn ffmpeg_args(&self, crf: u8, preset: &str) -> Vec<String> {
+            let mut args: Vec<String> = vec!["-c:v".to_string(), self.encoder().to_string(), "-crf".to_string(), crf.to_string()];
+
+            match self {
+                VideoCodec::H264 | VideoCodec::H265 => args.extend(["-preset".to_string(), preset.to_string()]),
+                VideoCodec::Vp9 => args.extend(["-b:v", "0", "-deadline", "good", "-row-mt", "1", "-cpu-used", preset].map(String::from)),
+                VideoCodec::Av1Aom => args.extend(["-b:v", "0", "-row-mt", "1", "-cpu-used", preset].map(String::from)),
+                VideoCodec::Av1Svt => args.extend(["-preset".to_string(), preset.to_string()]),
+            }
+            if *self == VideoCodec::H265 {
+                // Apple players only accept HEVC tagged as hvc1.
+                args.extend(["-tag:v".to_string(), "hvc1".to_string()]);
+            }
+
+            return args;
+        }
+    }
+
+    /// Audio codecs for the soundtrack of a video download.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum VideoAudioCodec {
+        Aac,
+        Opus,
+    }
+
+    impl VideoAudioCodec {
+        pub fn parse(raw: &str) -> Option<VideoAudioCodec> {
+            return match raw.to_ascii_lowercase().as_str() {
+                "aac" | "m4a" => Some(VideoAudioCodec::Aac),
+                "opus" => Some(VideoAudioCodec::Opus),
+                _ => None,
+            }
+        }
+
+        pub fn source_name(&self) -> &'static str {
+            return match self {
+                VideoAudioCodec::Aac => "mp4a",
+                VideoAudioCodec::Opus => "opus",
+            }
+        }
+
+        pub fn ffmpeg_args(&self) -> Vec<&'static str> {
+            return match self {
+                VideoAudioCodec::Aac => vec!["-c:a", "aac", "-b:a", "192k"],
+                VideoAudioCodec::Opus => vec!["-c:a", "libopus", "-b:a", "160k"],
+            }
+        }
+    }
+
+    pub const VIDEO_HEIGHTS: [u16; 8] = [144, 240, 360, 480, 720, 1080, 1440, 2160];
+
+    pub const MAX_SUBTITLE_TRACKS: usize = 8;
+
+    /// Output spec and stream selection for `dl_get_video`, all selection limits are upper bounds.
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct VideoOptions {
+        pub container: VideoContainer,
+        /// Target video codec, `None` keeps any source codec the container can hold.
+        pub codec: Option<VideoCodec>,
+        /// Target audio codec, `None` keeps any source codec the container can hold.
+        pub audio_codec: Option<VideoAudioCodec>,
+        pub crf: Option<u8>,
+        pub preset: Option<String>,
+        /// Maximum frame height, one of `VIDEO_HEIGHTS`.
+        pub height: Option<u16>,
+        pub fps: Option<u16>,
+        /// Maximum estimated size of video plus audio, in bytes.
+        pub max_filesize: Option<u64>,
+        pub clip: Option<Clip>,
+        /// Caption languages to mux in as soft subtitles.
+        pub subtitles: Vec<String>,
+    }
+
+    impl VideoOptions {
+        /// yt-dlp format selector. Streams with unknown fps or size are kept (`<=?`),
+        /// and a single muxed file is the fallback when no separate pair fits.
+        /// `server_max` is the configured size limit in bytes, applied on top of `max_filesize`.
+        pub fn ytdlp_selector(&self, server_max: Option<u64>) -> String {
+            let mut filters = String::new();
+            if let Some(height) = self.height {
+                filters.push_str(&format!("[height<=?{}]", height));
+            }
+            if let Some(fps) = self.fps {
+                filters.push_str(&format!("[fps<=?{}]", fps));
+            }
+
+            let max = match (self.max_filesize, server_max) {
+                (Some(requested), Some(server)) => Some(requested.min(server)),
+                (requested, server) => requested.or(server),
+            };
+            let size = match max {
+                Some(max) => format!("[filesize<=?{}]", max),
+                None => "".to_string(),
+            };
+
+            return format!("bestvideo{}{}+bestaudio{}/best{}{}", filters, size, size, filters, size);
+        }
+
+        /// Suffix for the cached file, taken from the stream yt-dlp actually picked so
+        /// that e.g. 1080 and 2160 limits on a 720p source share one file.
+        /// Empty without any limit so existing files stay valid.
+        pub fn cache_tag(&self, video: &SingleVideo) -> String {
+            let unrestricted = self.height.is_none() && self.fps.is_none() && self.max_filesize.is_none();
+            let mut tag = if unrestricted {
+                "".to_string()
+            } else {
+                match (video.height, video.fps) {
+                    (Some(height), Some(fps)) => format!(" {}p{}", height as u32, fps.round() as u32),
+                    (Some(height), None) => format!(" {}p", height as u32),
+                    _ => format!(" {}", video.format_id.as_deref().unwrap_or("custom").replace('+', "-")),
+                }
+            };
+            tag.push_str(&self.encoding_tag());
+            if let Some(clip) = self.clip {
+                tag.push_str(&clip.cache_tag());
+            }
+
+            return tag;
+        }
+
+        /// Suffix naming explicitly requested codecs and encoder settings.
+        fn encoding_tag(&self) -> String {
+            let mut tag = String::new();
+            if let Some(codec) = self.codec {
+                tag.push_str(&format!(" {}", codec.encoder().trim_start_matches("lib")));
+            }
+            if let Some(audio_codec) = self.audio_codec {
+                tag.push_str(&format!(" {}", audio_codec.source_name()));
+            }
+            if let Some(crf) = self.crf {
+                tag.push_str(&format!(" crf{}", crf));
+            }
+            if let Some(preset) = &self.preset {
+                tag.push_str(&format!(" {}", preset));
+            }
+            if !self.subtitles.is_empty() {
+                tag.push_str(&format!(" subs {}", self.subtitles.join(",")));
+            }
+            return tag;
+        }
+
+        /// ffmpeg codec arguments for a source with yt-dlp's `vcodec` / `acodec`.
+        /// Streams are copied whenever they already fit and no encoder setting was asked for.
+        pub fn ffmpeg_args(&self, vcodec: Option<&str>, acodec: Option<&str>) -> Vec<String> {
+            let tuned = self.crf.is_some() || self.preset.is_some();
+            let mut args: Vec<String> = Vec::new();
+
+            let copy_video = !tuned && vcodec.is_some_and(|v| match self.codec {
+                Some(codec) => codec.matches(v) && self.container.allows_video(v),
+                None => self.container.allows_video(v),
+            });
+            if copy_video {
+                args.extend(["-c:v".to_string(), "copy".to_string()]);
+            } else {
+                let codec = self.codec.unwrap_or(self.container.default_codec());
+                let preset = self.preset.clone().unwrap_or(codec.default_preset().to_string());
+                args.extend(codec.ffmpeg_args(self.crf.unwrap_or(codec.default_crf()), &preset));
+            }
+
+            let copy_audio = acodec.is_some_and(|a| match self.audio_codec {
+                Some(codec) => a.to_ascii_lowercase().starts_with(codec.source_name()) && self.container.allows_audio(a),
+                None => self.container.allows_audio(a),
+            });
+            if copy_audio {
+                args.extend(["-c:a".to_string(), "copy".to_string()]);
+            } else {
+                let codec = self.audio_codec.unwrap_or(self.container.default_audio_codec());
+                args.extend(codec.ffmpeg_args().iter().map(|x| x.to_string()));
+            }
+
+            if self.container == VideoContainer::Mp4 {
+                args.extend(["-movflags".to_string(), "+faststart".to_string()]);
+            }
+
+            return args;
+        }
+
+        /// Mapping, codec and language tags for subtitle `tracks` given as inputs after the video.
+        pub fn subtitle_args(&self, tracks: &[SubtitleTrack]) -> Vec<String> {
+            if tracks.is_empty() {
+                return Vec::new();
+            }
+
+            let mut args: Vec<String> = ["-map", "0:v:0", "-map", "0:a:0?"].iter().map(|x| x.to_string()).collect();
+            for idx in 0..tracks.len() {
+                args.extend(["-map".to_string(), format!("{}:0", idx + 1)]);
+            }
+            args.extend(["-c:s".to_string(), self.container.subtitle_codec().to_string()]);
+            for (idx, track) in tracks.iter().enumerate() {
+                args.extend([format!("-metadata:s:s:{}", idx), format!("language={}", subtitles::iso639_2(&track.language))]);
+                args.extend([format!("-metadata:s:s:{}", idx), format!("title={}", track.title())]);
+            }
+
+            return args;
+        }
+
+        /// Suffix for playlist archives, where there is no single picked stream.
+        pub fn playlist_tag(&self) -> String {
+            let mut tag = String::new();
+            if let Some(height) = self.height {
+                tag.push_str(&format!(" {}p", height));
+            }
+            if let Some(fps) = self.fps {
+                tag.push_str(&format!(" {}fps", fps));
+            }
+            if let Some(max) = self.max_filesize {
+                tag.push_str(&format!(" max{}", max));
+            }
+            tag.push_str(&self.encoding_tag());
+            return tag;
+        }
+    }
+
+    /// Parses sizes like `50M`, `1.5G`, `800k` or plain bytes (binary multiples).
+    pub fn parse_filesize(raw: &str) -> Option<u64> {
+        let raw = raw.trim();
+        let raw = raw.strip_suffix(['b', 'B']).unwrap_or(raw);
+        let (num, mult) = match raw.char_indices().last()? {
+            (i, 'k' | 'K') => (&raw[..i], 1u64 << 10),
+            (i, 'm' | 'M') => (&raw[..i], 1u64 << 20),
+            (i, 'g' | 'G') => (&raw[..i], 1u64 << 30),
+            _ => (raw, 1),
+        };
+
+        let value = num.trim().parse::<f64>().ok().filter(|v| v.is_finite() && *v >= 0.0)?;
+        return Some((value * mult as f64) as u64);
+    }
+
+    /// What `process_audio` learned about the source on the way.
+    #[derive(Debug, Clone, Copy, Default, PartialEq)]
+    pub struct ProcessedAudio {
+        pub loudness: Option<LoudnessMeasurement>,
+        pub silence: Option<SilenceTrim>,
+    }
+
+    /// Converts `input` to `options.format`, returning the loudness measured when normalizing.
+    /// `trim` cuts the input when yt-dlp could not download just the clip.
+    pub async fn process_audio(transcoder: &dyn Transcoder, input: &Path, filename: &str, options: &AudioOptions, source_codec: Option<&str>, trim: Option<&Clip>) -> Result<ProcessedAudio, DownloaderError>{
+        let output = format!("{}.{}", filename, options.format.extension());
+
+        let codec_args = match options.passthrough_args(source_codec, trim) {
+            Some(args) => {
+                println!("{} source, copying stream without re-encoding", source_codec.unwrap_or_default());
+                args
+            },
+            None => options.ffmpeg_args(),
+        };
+
+        let mut trim_args = trim.map(|c| c.ffmpeg_input_args()).unwrap_or_default();
+
+        let silence = match &options.trim_silence {
+            Some(settings) => {
+                let log = transcoder.run(&settings.detect_job(input).input_args(trim_args.clone())).await?;
+
+                // The detection pass reports the whole input, a clip only sees part of it.
+                let offset = trim.map(|c| c.start as f64).unwrap_or_default();
+                let total = log.lines().find_map(crate::progress::parse_ffmpeg_duration).map(|d| d - offset);
+                let length = match (trim.and_then(|c| c.end), total) {
+                    (Some(end), Some(total)) => (end as f64 - offset).min(total),
+                    (Some(end), None) => end as f64 - offset,
+                    (None, total) => total.unwrap_or_default(),
+                };
+
+                let cut = silence::trim_from_log(&log, length);
+                if !cut.is_empty() {
+                    println!("Cutting {:.2}s of leading and {:.2}s of trailing silence", cut.leading, cut.trailing);
+                    trim_args = cut.ffmpeg_input_args(offset, length);
+                }
+                Some(cut)
+            },
+            None => None,
+        };
+
+        let mut job = TranscodeJob::new(input, Path::new(&output)).input_args(trim_args.clone()).output_args(codec_args);
+
+        let loudness = match &options.normalize {
+            Some(target) => {
+                let log = transcoder.run(&loudness::measure_job(input, target).input_args(trim_args)).await?;
+                let measured = loudness::parse_measurement(&log)
+                    .ok_or_else(|| DownloaderError::Transcode("loudnorm did not report measurements".to_string()))?;
+                println!("Measured {} LUFS, {} dBTP", measured.input_i, measured.input_tp);
+
+                // loudnorm resamples to 192 kHz internally, which most encoders reject.
+                job = job.audio_filter(&target.filter(&measured)).output_args(["-ar", "48000"]);
+                Some(measured)
+            },
+            None => None,
+        };
+
+        transcoder.run(&job).await?;
+
+        return Ok(ProcessedAudio { loudness, silence });
+    }
+    pub async fn process_video(transcoder: &dyn Transcoder, input: &Path, filename: &str, options: &VideoOptions, source: &SingleVideo, trim: Option<&Clip>, subtitles: &[SubtitleTrack]) -> Result<(), DownloaderError>{
+        let output = format!("{}.{}", filename, options.container.extension());
+
+        let codec_args = options.ffmpeg_args(source.vcodec.as_deref(), source.acodec.as_deref());
+
+        let mut job = TranscodeJob::new(input, Path::new(&output))
+            .input_args(trim.map(|c| c.ffmpeg_input_args()).unwrap_or_default())
+            .output_args(codec_args)
+            .output_args(options.subtitle_args(subtitles));
+        for track in subtitles {
+            job = job.input(&track.path);
+        }
+        transcoder.run(&job).await?;
+
+        return Ok(());
+    }
+
+    /// Downloads `url` with yt-dlp into `[key].ext`, reporting its progress lines.
+    /// The youtube_dl crate always passes `--no-progress`, so the download itself runs here.
+    pub async fn fetch_media(url: &str, key: &str, ytdl_path: &Path, format: &str, extract_audio: bool, section: Option<&str>) -> Result<(), DownloaderError> {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+        println!("Downloading video: {}", url);
+
+        let template = format!("[{}].%(ext)s", key);
+        let mut args = vec!["-f", format, "-o", &template, "--socket-timeout", "15", "--newline", "--no-playlist"];
+        if extract_audio {
+            args.push("--extract-audio");
+        }
+        if let Some(section) = section {
+            args.extend(["--download-sections", section]);
+            if !extract_audio {
+                // Without this video sections start at the previous keyframe.
+                args.push("--force-keyframes-at-cuts");
+            }
+        }
+        let ffmpeg_location = crate::transcoder::ffmpeg().ok().map(|f| f.path.to_string_lossy().to_string());
+        if let Some(location) = &ffmpeg_location {
+            args.extend(["--ffmpeg-location", location.as_str()]);
+        }
+        args.push(url);
+
+        let mut child = tokio::process::Command::new(ytdl_path)
+            .args(&args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| DownloaderError::Upstream(format!("could not start yt-dlp: {}", e)))?;
+
+        let mut stderr = child.stderr.take().unwrap();
+        let stderr_task = tokio::spawn(async move {
+            let mut buf = String::new();
+            let _ = stderr.read_to_string(&mut buf).await;
+            buf
+        });
+
+        let mut lines = tokio::io::BufReader::new(child.stdout.take().unwrap()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if let Some(event) = progress::parse_ytdlp_progress(&line) {
+                crate::jobs::report_progress(event);
+            }
+        }
+
+        let status = child.wait().await?;
+        let stderr = stderr_task.await.unwrap_or_default();
+
+        if !status.success() {
+            return Err(DownloaderError::Upstream(format!("yt-dlp exited with {}: {}", status, stderr.trim())));
+        }
+
+        return Ok(());
+    }
+    
+    /// Downloads `clip` alone through `--download-sections` where yt-dlp manages, otherwise the
+    /// whole media. Returns the clip still to be cut by the transcoder.
+    pub async fn fetch_clip(url: &str, key: &str, ytdl_path: &Path, format: &str, extract_audio: bool, clip: Option<&Clip>) -> Result<Option<Clip>, DownloaderError> {
+        if let Some(clip) = clip {
+            match fetch_media(url, key, ytdl_path, format, extract_audio, Some(&clip.section())).await {
+                Ok(()) => return Ok(None),
+                Err(e) => {
+                    println!("Section download failed, trimming with ffmpeg instead: {}", e);
+                    let root = env::current_dir().unwrap();
+                    while let Some(partial) = find_download(&root, key) {
+                        fs::remove_file(partial)?;
+                    }
+                },
+            }
+        }
+
+        fetch_media(url, key, ytdl_path, format, extract_audio, None).await?;
+
+        return Ok(clip.copied());
+    }
+
+    pub async fn get_audio(id: &VideoId) -> Result<String, DownloaderError> {
+        return get_audio_source(id).await.map(|source| source.url);
+    }
+
+    /// Direct URL of the best audio stream, with what is needed to request it.
+    pub async fn get_audio_source(id: &VideoId) -> Result<StreamSource, DownloaderError> {
+        let _root: PathBuf = env::current_dir().unwrap();
+        let (ytdlp_path, _) = setup(&_root).unwrap();
+
+        println!("Video ID: {:?}", id);
+        let video = match download_audio(id, &ytdlp_path, Some(false)).await {
+            Some(video) => video,
+            None => return Err(DownloaderError::NotFound(format!("{} not found", id))),
+        };
+        println!("Title: {:?}, channel: {:?}", video.title, video.channel);
+
+        let url = match video.url {
+            Some(url) => url,
+            None => return Err(DownloaderError::Upstream(format!("No stream URL for {}", id))),
+        };
+        let headers = video.http_headers.unwrap_or_default().into_iter()
+            .filter_map(|(k, v)| Some((k, v?)))
+            .collect();
+
+        return Ok(StreamSource {
+            url,
+            content_type: streaming::content_type(video.ext.as_deref(), video.acodec.as_deref()).to_string(),
+            headers,
+        });
+    }
+    
+    pub async fn get_video(id: &VideoId) -> Result<PathBuf, DownloaderError> {
+        let _root: PathBuf = env::current_dir().unwrap();
+        let (_, tmp_path) = setup(&_root).unwrap();
+
+        let url = id.watch_url();
+        println!("{}", &url);
+        let i = Id::from_raw(&url)?;
+        let v = Video::from_id(i.into_owned()).await?;
+
+        let path = match v.best_quality() {
+            Some(stream) => stream.download_to_dir(tmp_path).await?,
+            None => return Err(DownloaderError::NotFound(format!("No streams for {}", id))),
+        };
+
+        return Ok(path);
+    }
+
+    pub async fn dl_get_audio(id: &VideoId, options: &AudioOptions) -> Result<PathBuf, DownloaderError> {
+        println!("Video ID: {:?}", id);
+
+        return dl_get_audio_url(&id.watch_url(), options).await;
+    }
+
+    /// Downloads the audio of any URL yt-dlp understands and transcodes it to `options.format`.
+    pub async fn dl_get_audio_url(url: &str, options: &AudioOptions) -> Result<PathBuf, DownloaderError> {
+        let _root: PathBuf = env::current_dir().unwrap();
+        let (ytdlp_path, tmp_path) = setup(&_root).unwrap();
+
+        let selector = options.format.ytdlp_selector();
+
+        return match get_metadata_selector(url, &ytdlp_path, selector).await {
+            Some(vmetadata) => {
+                let c : super::Configuration = envy::from_env::<super::Configuration>().expect("Provide config.");
+                
+                let full_duration = vmetadata.duration.as_ref().and_then(|d| d.as_f64()).unwrap_or_default();
+                let duration = clip_length(options.clip.as_ref(), full_duration)?;
+
+                if c.limit_duration && duration > (c.max_audio_duration_minutes as f64 * 60.0) {
+                    return Err(DownloaderError::DurationExceeded(format!("Audio duration exceeds maximum of {} hours", (c.max_audio_duration_minutes as f64 / 60.0))));
+                }
+
+                let key = media_key(&vmetadata);
+                let nftitle: String = sanitize_title(&vmetadata.title);
+
+                let fname = format!("{} [{}]{}.{}", nftitle, key, options.cache_tag(), options.format.extension());
+
+                let tmp_fpath = tmp_path.join(&fname);
+                if tmp_fpath.exists() {
+                    println!("File {} found in storage", tmp_fpath.to_str().unwrap());
+
+                    if let Some(cut) = CacheMeta::read(&tmp_fpath).and_then(|m| m.silence) {
+                        crate::jobs::report_result("silence", &cut);
+                    }
+                    if options.replaygain {
+                        match ensure_replaygain(crate::transcoder::ffmpeg()?, &tmp_fpath, options.format, duration).await {
+                            Ok(tagged) => return Ok(tagged),
+                            Err(e) => println!("{}", e),
+                        }
+                    }
+                    return Ok(tmp_fpath);
+                }
+                
+                crate::jobs::report(crate::jobs::JobState::Downloading);
+                let trim = fetch_clip(url, &key, &ytdlp_path, selector, true, options.clip.as_ref()).await?;
+                let input = match find_download(&_root, &key) {
+                    Some(input) => input,
+                    None => return Err(DownloaderError::Upstream(format!("Download of {} failed", url))),
+                };
+
+                println!("processing file");
+                crate::jobs::report(crate::jobs::JobState::Transcoding);
+                let processed = process_audio(crate::transcoder::ffmpeg()?, &input, &format!("{} [{}]{}", nftitle, key, options.cache_tag()), options, vmetadata.acodec.as_deref(), trim.as_ref()).await?;
+                fs::remove_file(&input)?;
+
+                let metadata = MediaMetadata::from_single_video(&vmetadata);
+                let mut tags = TrackTags::from_video(&vmetadata, &metadata);
+                tags.cover = fetch_cover(&metadata).await;
+                if let Err(e) = write_tags(&_root.join(&fname), options.format, &tags) {
+                    println!("{}", e);
+                }
+                println!("moving file");
+                let p = move_video_to_temp(&_root, &fname)?;
+                println!("move finished");
+
+                if let Some(cut) = processed.silence {
+                    crate::jobs::report_result("silence", &cut);
+                }
+                let meta = CacheMeta { loudness: processed.loudness, silence: processed.silence, duration: Some(duration), ..CacheMeta::default() };
+                if let Err(e) = meta.write(&p) {
+                    println!("Could not write cache metadata for {}: {}", fname, e);
+                }
+                if options.replaygain {
+                    match ensure_replaygain(crate::transcoder::ffmpeg()?, &p, options.format, duration).await {
+                        Ok(tagged) => return Ok(tagged),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                Ok(p)
+            },
+            None => {
+                Err(DownloaderError::NotFound(format!("{} not found", url)))
+            }
+        }
+    }
+
+    pub async fn dl_get_video(id: &VideoId, process: bool, options: &VideoOptions) -> Result<PathBuf, DownloaderError> {
+        println!("Video ID: {:?}", id);
+
+        return dl_get_video_url(&id.watch_url(), process, options).await;
+    }
+
+    /// Downloads the video of any URL yt-dlp understands, converting it to `options.container` when `process` is set.
+    pub async fn dl_get_video_url(url: &str, process: bool, options: &VideoOptions) -> Result<PathBuf, DownloaderError> {
+        let _root: PathBuf = env::current_dir().unwrap();
+        let (ytdlp_path, tmp_path) = setup(&_root).unwrap();
+
+        let c : super::Configuration = envy::from_env::<super::Configuration>().expect("Provide config.");
+        let server_max = c.max_video_filesize_mb.saturating_mul(1024 * 1024);
+        let selector = options.ytdlp_selector(Some(server_max));
+
+        return match get_metadata_selector(url, &ytdlp_path, &selector).await {
+            Some(vmetadata) => {
+
+                let full_duration = vmetadata.duration.as_ref().and_then(|d| d.as_f64()).unwrap_or_default();
+                let duration = clip_length(options.clip.as_ref(), full_duration)?;
+                
+                if c.limit_duration && duration > (c.max_video_duration_minutes as f64 * 60.0) {
+                    return Err(DownloaderError::DurationExceeded(format!("Video duration exceeds maximum of {} minutes", c.max_video_duration_minutes)));
+                }
+
+                let max_filesize = server_max.min(options.max_filesize.unwrap_or(u64::MAX));
+                let mut filesize = vmetadata.filesize.map(|s| s as f64).or(vmetadata.filesize_approx).unwrap_or_default();
+                if full_duration > 0.0 {
+                    filesize *= duration / full_duration;
+                }
+                if filesize > max_filesize as f64 {
+                    return Err(DownloaderError::FileTooLarge(format!("Estimated size of {} MiB exceeds maximum of {} MiB", (filesize / 1048576.0).ceil(), max_filesize / 1048576)));
+                }
+
+                let key = media_key(&vmetadata);
+                let nftitle: String = sanitize_title(&vmetadata.title);
+                let tag = options.cache_tag(&vmetadata);
+
+                let fname = format!("{} [{}]{}.{}", nftitle, key, tag, options.container.extension());
+
+                let tmp_fpath = tmp_path.join(&fname);
+                if process && tmp_fpath.exists() {
+                    println!("File {} found in storage", tmp_fpath.to_str().unwrap());
+
+                    return Ok(tmp_fpath);
+                }
+                
+                crate::jobs::report(crate::jobs::JobState::Downloading);
+                let trim = fetch_clip(url, &key, &ytdlp_path, &selector, false, options.clip.as_ref()).await?;
+                println!("Title: {:?}, channel: {:?}", vmetadata.title, vmetadata.channel);
+
+                let input = match find_download(&_root, &key) {
+                    Some(input) => input,
+                    None => return Err(DownloaderError::Upstream(format!("Download of {} failed", url))),
+                };
+                let _fnamewext = format!("{} [{}]{}", &nftitle, &key, tag);
+
+                let out_name = if process {
+                    println!("processing file");
+                    crate::jobs::report(crate::jobs::JobState::Transcoding);
+                    let subtitles = fetch_subtitle_tracks(url, &key, &ytdlp_path, &vmetadata, options, duration).await;
+                    let res = process_video(crate::transcoder::ffmpeg()?, &input, &_fnamewext, options, &vmetadata, trim.as_ref(), &subtitles).await;
+                    for track in &subtitles {
+                        let _ = fs::remove_file(&track.path);
+                    }
+                    res?;
+                    fs::remove_file(&input)?;
+                    fname
+                } else {
+                    let ext = input.extension().and_then(|x| x.to_str()).unwrap_or("webm").to_string();
+                    let out_name = format!("{}.{}", &_fnamewext, ext);
+                    fs::rename(&input, _root.join(&out_name))?;
+                    out_name
+                };
+                println!("moving file");
+                let p = move_video_to_temp(&_root, &out_name)?;
+                println!("move finished");
+                Ok(p)
+            },
+            None => {
+                Err(DownloaderError::NotFound(format!("{} not found", url)))
+            }
+        }
+    }
+
+    /// Stable cache key for a downloaded item. YouTube keeps the bare video ID so
+    /// existing cache entries stay valid, everything else is `extractor-id`.
+    pub fn media_key(video: &SingleVideo) -> String {
+        let extractor = video.extractor_key.as_deref()
+            .or(video.extractor.as_deref())
+            .unwrap_or("generic")
+            .to_ascii_lowercase();
+
+        let key = if extractor == "youtube" {
+            video.id.clone()
+        } else {
+            format!("{}-{}", extractor, video.id)
+        };
+
+        return key.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+    }
+
+    /// Finds the file yt-dlp wrote for `key` using the `[key].ext` output template.
+    pub fn find_download(root: &Path, key: &str) -> Option<PathBuf> {
+        let prefix = format!("[{}].", key);
+
+        return fs::read_dir(root).ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .find(|p| {
+                let name = p.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+                name.starts_with(&prefix) && !name.ends_with(".part") && !name.ends_with(".ytdl")
+            });
+    }
+
+    /// Checks `url` against the configured host allowlist. Subdomains of an
+    /// allowed host are accepted too.
+    pub fn is_allowed_url(url: &str, allowed_hosts: &[String]) -> bool {
+        let url = match Url::parse(url) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return false;
+        }
+
+        let host = match url.host_str() {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return false,
+        };
+
+        return allowed_hosts.iter()
+            .map(|h| h.trim().to_ascii_lowercase())
+            .filter(|h| !h.is_empty())
+            .any(|h| host == h || host.ends_with(&format!(".{}", h)));
+    }
+
+    /// Downloads every entry of a playlist through `dl_get_audio` / `dl_get_video`
+    /// and packs the results into one ZIP, numbered in playlist order.
+    pub async fn dl_get_playlist(list: &PlaylistId, format: MediaFormat, options: &AudioOptions, video: &VideoOptions) -> Result<PathBuf, DownloaderError> {
+        let _root: PathBuf = env::current_dir().unwrap();
+        let (ytdlp_path, tmp_path) = setup(&_root).unwrap();
+
+        println!("Playlist ID: {:?}", list);
+
+        let playlist = match get_playlist(list, &ytdlp_path).await {
+            Some(playlist) => playlist,
+            None => return Err(DownloaderError::NotFound(format!("Playlist {} not found", list))),
+        };
+        let entries = playlist.entries.unwrap_or_default();
+
+        let c : super::Configuration = envy::from_env::<super::Configuration>().expect("Provide config.");
+
+        if entries.is_empty() {
+            return Err(DownloaderError::NotFound(format!("Playlist {} is empty", list)));
+        }
+        if entries.len() > c.max_playlist_items as usize {
+            return Err(DownloaderError::PlaylistTooLarge(c.max_playlist_items));
+        }
+        let max_size = c.max_playlist_size_mb.saturating_mul(1024 * 1024);
+        if playlist_size(&entries) > max_size {
+            return Err(DownloaderError::FileTooLarge(format!("Playlist exceeds maximum size of {} MiB", c.max_playlist_size_mb)));
+        }
+
+        let title = sanitize_title(playlist.title.as_deref().unwrap_or("playlist"));
+        let album_gain = options.replaygain && matches!(format, MediaFormat::Audio(_));
+        let tag = match format {
+            MediaFormat::Video(_) => video.playlist_tag(),
+            MediaFormat::Audio(_) if album_gain => format!("{} rg", options.cache_tag()),
+            MediaFormat::Audio(_) => options.cache_tag(),
+        };
+        let zip_path = tmp_path.join(format!("{} [{}]{} {}.zip", title, list, tag, format.extension()));
+
+        if zip_path.exists() {
+            println!("File {} found in storage", zip_path.to_str().unwrap());
+
+            return Ok(zip_path);
+        }
+
+        let width = entries.len().to_string().len().max(2);
+        let mut files: Vec<(String, PathBuf)> = Vec::new();
+        // Flat playlist entries rarely know their size, so also count what was actually downloaded.
+        let mut total_size: u64 = 0;
+
+        for (idx, entry) in entries.iter().enumerate() {
+            let id = match VideoId::parse(&entry.id) {
+                Some(id) => id,
+                None => {
+                    println!("Skipping playlist entry with invalid id {:?}", entry.id);
+                    continue;
+                }
+            };
+
+            let res = match format {
+                MediaFormat::Video(_) => dl_get_video(&id, true, video).await,
+                MediaFormat::Audio(_) => dl_get_audio(&id, options).await,
+            };
+
+            match res {
+                Ok(p) => {
+                    total_size = total_size.saturating_add(fs::metadata(&p).map(|m| m.len()).unwrap_or_default());
+                    if total_size > max_size {
+                        return Err(DownloaderError::FileTooLarge(format!("Playlist exceeds maximum size of {} MiB", c.max_playlist_size_mb)));
+                    }
+                    let name = p.file_name().unwrap().to_string_lossy().to_string();
+                    files.push((format!("{:0width$} - {}", idx + 1, name, width = width), p));
+                },
+                Err(e) => {
+                    println!("Skipping playlist entry {}: {}", id, e);
+                }
+            }
+        }
+
+        if files.is_empty() {
+            return Err(DownloaderError::Upstream("No playlist entries could be downloaded".to_string()));
+        }
+
+        // An archive missing entries goes under its own name, so the next request retries the
+        // failed ones instead of being served the incomplete cache.
+        let zip_path = if files.len() < entries.len() {
+            println!("Only {} of {} playlist entries could be downloaded", files.len(), entries.len());
+            tmp_path.join(format!("{} [{}]{} {} partial {} of {}.zip", title, list, tag, format.extension(), files.len(), entries.len()))
+        } else {
+            zip_path
+        };
+
+        // Album gain only holds within this playlist, so tag copies instead of the shared cache files.
+        let mut staged: Vec<PathBuf> = Vec::new();
+        let res = if album_gain {
+            stage_album_gain(&tmp_path, &list.to_string(), options.format, &mut files, &mut staged)
+        } else {
+            Ok(())
+        };
+
+        let res = match res {
+            Ok(()) => {
+                let out = zip_path.clone();
+                tokio::task::spawn_blocking(move || write_zip(&files, &out)).await
+                    .map_err(|e| DownloaderError::Io(io::Error::other(e)))
+                    .and_then(|r| r.map_err(DownloaderError::from))
+            },
+            Err(e) => Err(e),
+        };
+        for copy in staged {
+            let _ = fs::remove_file(copy);
+        }
+        res?;
+
+        println!("Packed playlist into {}", zip_path.to_str().unwrap());
+
+        return Ok(zip_path);
+    }
+
+    /// Size in bytes yt-dlp reports for `entries`, counting those without one as empty.
+    pub fn playlist_size(entries: &[SingleVideo]) -> u64 {
+        entries.iter()
+            .map(|e| e.filesize.map(|s| s as f64).or(e.filesize_approx).unwrap_or_default().max(0.0) as u64)
+            .fold(0, u64::saturating_add)
+    }
+
+    /// Points `files` at hidden copies tagged with track and album gain, adding every
+    /// copy to `staged` so the caller can clean up after failures.
+    pub fn stage_album_gain(tmp_path: &Path, prefix: &str, format: AudioFormat, files: &mut [(String, PathBuf)], staged: &mut Vec<PathBuf>) -> Result<(), DownloaderError> {
+        let tracks: Vec<(LoudnessMeasurement, f64)> = files.iter()
+            .filter_map(|(_, p)| CacheMeta::read(p))
+            .filter_map(|m| Some((m.output_loudness?, m.duration.unwrap_or(1.0))))
+            .collect();
+
+        let (album_i, album_tp) = match loudness::album_loudness(&tracks) {
+            Some(album) if tracks.len() == files.len() => album,
+            _ => {
+                println!("Skipping album gain for {}, not every track was measured", prefix);
+                return Ok(());
+            },
+        };
+
+        for (idx, ((name, p), (measured, _))) in files.iter_mut().zip(&tracks).enumerate() {
+            let copy = tmp_path.join(format!(".{} {} {}", prefix, idx, name));
+            staged.push(copy.clone());
+            fs::copy(&*p, &copy)?;
+            write_replaygain(&copy, format, &ReplayGain::track(measured).with_album(album_i, album_tp))?;
+            *p = copy;
+        }
+
+        Ok(())
+    }
+
+    /// Downloads the audio of `url` once and cuts it into one tagged track per
+    /// chapter, packed as ZIP.
+    pub async fn dl_get_chapters(url: &str, options: &AudioOptions) -> Result<PathBuf, DownloaderError> {
+        let _root: PathBuf = env::current_dir().unwrap();
+        let (ytdlp_path, tmp_path) = setup(&_root).unwrap();
+
+        let vmetadata = match get_metadata_selector(url, &ytdlp_path, options.format.ytdlp_selector()).await {
+            Some(vmetadata) => vmetadata,
+            None => return Err(DownloaderError::NotFound(format!("{} not found", url))),
+        };
+        let duration = vmetadata.duration.as_ref().and_then(|d| d.as_f64()).unwrap_or_default();
+        let chapters = video_chapters(&vmetadata, duration);
+        if chapters.len() < 2 {
+            return Err(DownloaderError::NotFound(format!("{} has no chapters", url)));
+        }
+
+        let key = media_key(&vmetadata);
+        let tag = if options.replaygain { format!("{} rg", options.cache_tag()) } else { options.cache_tag() };
+        let zip_path = tmp_path.join(format!("{} [{}]{} chapters {}.zip", sanitize_title(&vmetadata.title), key, tag, options.format.extension()));
+
+        if zip_path.exists() {
+            println!("File {} found in storage", zip_path.to_str().unwrap());
+
+            return Ok(zip_path);
+        }
+
+        // Gain is measured per part below, the whole file's would be meaningless.
+        let source = dl_get_audio_url(url, &AudioOptions { replaygain: false, ..options.clone() }).await?;
+
+        let metadata = MediaMetadata::from_single_video(&vmetadata);
+        let mut tags = TrackTags::from_video(&vmetadata, &metadata);
+        tags.album = Some(tags.album.unwrap_or(metadata.title.clone()));
+        tags.cover = fetch_cover(&metadata).await;
+
+        crate::jobs::report(crate::jobs::JobState::Transcoding);
+        let mut files: Vec<(String, PathBuf)> = Vec::new();
+        let res = cut_chapters(crate::transcoder::ffmpeg()?, &source, &tmp_path, &key, &chapters, options, &tags, &mut files).await;
+
+        let res = match res {
+            Ok(()) => {
+                let out = zip_path.clone();
+                let parts = files.clone();
+                tokio::task::spawn_blocking(move || write_zip(&parts, &out)).await
+                    .map_err(|e| DownloaderError::Io(io::Error::other(e)))
+                    .and_then(|r| r.map_err(DownloaderError::from))
+            },
+            Err(e) => Err(e),
+        };
+        for (_, part) in files {
+            let _ = fs::remove_file(part);
+        }
+        res?;
+
+        println!("Packed {} chapters into {}", chapters.len(), zip_path.to_str().unwrap());
+
+        return Ok(zip_path);
+    }
+
+    /// Copies each chapter of `source` into its own hidden file in `tmp_path` and tags it,
+    /// adding every finished part to `files` so the caller can clean up after failures.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn cut_chapters(transcoder: &dyn Transcoder, source: &Path, tmp_path: &Path, key: &str, chapters: &[Chapter], options: &AudioOptions, tags: &TrackTags, files: &mut Vec<(String, PathBuf)>) -> Result<(), DownloaderError> {
+        let width = chapters.len().to_string().len().max(2);
+        let mut measured: Vec<(LoudnessMeasurement, f64)> = Vec::new();
+
+        for (idx, chapter) in chapters.iter().enumerate() {
+            let name = format!("{:0width$} - {}.{}", idx + 1, sanitize_title(&chapter.title), options.format.extension(), width = width);
+            let part = tmp_path.join(format!(".{} {}", key, name));
+
+            let job = TranscodeJob::new(source, &part)
+                .input_args(chapter.ffmpeg_input_args())
+                .output_args(["-vn", "-map_metadata", "-1", "-c:a", "copy"]);
+            transcoder.run(&job).await?;
+            files.push((name, part.clone()));
+
+            let mut track = tags.clone();
+            track.title = chapter.title.clone();
+            track.track = Some((idx as u32 + 1, chapters.len() as u32));
+            if let Err(e) = write_tags(&part, options.format, &track) {
+                println!("{}", e);
+            }
+
+            if options.replaygain {
+                let log = transcoder.run(&loudness::measure_job(&part, &LoudnessTarget::REPLAYGAIN)).await?;
+                let m = loudness::parse_measurement(&log)
+                    .ok_or_else(|| DownloaderError::Transcode("loudnorm did not report measurements".to_string()))?;
+                measured.push((m, chapter.length()));
+            }
+        }
+
+        // The chapters make up one release, so they share album gain.
+        if let Some((album_i, album_tp)) = loudness::album_loudness(&measured) {
+            for ((_, part), (m, _)) in files.iter().zip(&measured) {
+                write_replaygain(part, options.format, &ReplayGain::track(m).with_album(album_i, album_tp))?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Writes `files` into a stored (uncompressed) ZIP, using the given archive names.
+    pub fn write_zip(files: &[(String, PathBuf)], out: &Path) -> Result<(), io::Error> {
+        let partial = out.with_extension("zip.part");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&partial)?);
+        let options = zip::write::FileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored)
+            .large_file(true);
+
+        for (name, path) in files {
+            zip.start_file(name.as_str(), options)?;
+            io::copy(&mut fs::File::open(path)?, &mut zip)?;
+        }
+        zip.finish()?;
+
+        return fs::rename(partial, out);
+    }
+
+    pub fn sanitize_title(title: &str) -> String {
+        return title.chars().filter(|c| c.is_ascii()).collect::<String>().replace(['/', '|'], "");
+    }
+
+    /// Length of every YouTube video ID.
+    pub const VIDEO_ID_LEN: usize = 11;
+
+    /// A validated 11 character YouTube video ID.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub struct VideoId(String);
+
+    impl VideoId {
+        pub fn parse(raw: &str) -> Option<VideoId> {
+            let valid = raw.len() == VIDEO_ID_LEN
+                && raw.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+            if !valid {
+                return None;
+            }
+
+            return Some(VideoId(raw.to_string()));
+        }
+
+        pub fn as_str(&self) -> &str {
+            return &self.0;
+        }
+
+        pub fn watch_url(&self) -> String {
+            return format!("https://www.youtube.com/watch?v={}", self.0);
+        }
+    }
+
+    impl fmt::Display for VideoId {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            return f.write_str(&self.0);
+        }
+    }
+
+    impl PartialEq<&str> for VideoId {
+        fn eq(&self, other: &&str) -> bool {
+            return self.0 == *other;
+        }
+    }
+
+    /// A validated YouTube playlist ID (`list=`).
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub struct PlaylistId(String);
+
+    impl PlaylistId {
+        pub fn parse(raw: &str) -> Option<PlaylistId> {
+            let valid = (2..=64).contains(&raw.len())
+                && raw.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+            if !valid {
+                return None;
+            }
+
+            return Some(PlaylistId(raw.to_string()));
+        }
+
+        pub fn as_str(&self) -> &str {
+            return &self.0;
+        }
+
+        pub fn playlist_url(&self) -> String {
+            return format!("https://www.youtube.com/playlist?list={}", self.0);
+        }
+    }
+
+    impl fmt::Display for PlaylistId {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            return f.write_str(&self.0);
+        }
+    }
+
+    impl PartialEq<&str> for PlaylistId {
+        fn eq(&self, other: &&str) -> bool {
+            return self.0 == *other;
+        }
+    }
+
+    /// Everything we keep from a pasted YouTube link.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct VideoLink {
+        pub id: VideoId,
+        /// Start offset in seconds taken from `t=` / `start=`.
+        pub start: Option<u64>,
+        /// Playlist the video was opened from (`list=`).
+        pub list: Option<PlaylistId>,
+    }
+
+    /// Part of a media file to download, in whole seconds.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Clip {
+        pub start: u64,
+        /// End of the clip, `None` runs to the end of the media.
+        pub end: Option<u64>,
+    }
+
+    impl Clip {
+        /// Length of the clip within media of `total` seconds.
+        pub fn length(&self, total: f64) -> f64 {
+            let end = self.end.map(|e| (e as f64).min(total)).unwrap_or(total);
+            return (end - self.start as f64).max(0.0);
+        }
+
+        /// yt-dlp `--download-sections` spec.
+        pub fn section(&self) -> String {
+            return match self.end {
+                Some(end) => format!("*{}-{}", self.start, end),
+                None => format!("*{}-inf", self.start),
+            }
+        }
+
+        /// Input options cutting the clip out of a full download.
+        pub fn ffmpeg_input_args(&self) -> Vec<String> {
+            let mut args = vec!["-ss".to_string(), self.start.to_string()];
+            if let Some(end) = self.end {
+                args.extend(["-t".to_string(), (end - self.start).to_string()]);
+            }
+            return args;
+        }
+
+        pub fn cache_tag(&self) -> String {
+            return match self.end {
+                Some(end) => format!(" {}-{}s", self.start, end),
+                None => format!(" {}-end", self.start),
+            }
+        }
+    }
+
+    /// Seconds that will actually be produced, rejecting clips starting past the end.
+    pub fn clip_length(clip: Option<&Clip>, total: f64) -> Result<f64, DownloaderError> {
+        return match clip {
+            Some(clip) if total > 0.0 && clip.start as f64 >= total => {
+                Err(DownloaderError::InvalidInput(format!("Clip starts at {}s but the media is only {}s long", clip.start, total as u64)))
+            },
+            Some(clip) if total > 0.0 => Ok(clip.length(total)),
+            _ => Ok(total),
+        }
+    }
+
+    /// One part of a video cut at chapter boundaries, in seconds.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Chapter {
+        pub title: String,
+        pub start: f64,
+        pub end: f64,
+    }
+
+    impl Chapter {
+        pub fn length(&self) -> f64 {
+            return self.end - self.start;
+        }
+
+        pub fn ffmpeg_input_args(&self) -> Vec<String> {
+            return vec!["-ss".to_string(), format!("{:.3}", self.start), "-t".to_string(), format!("{:.3}", self.length())];
+        }
+    }
+
+    /// Chapters yt-dlp reports for `video`, falling back to timestamps in its description.
+    pub fn video_chapters(video: &SingleVideo, duration: f64) -> Vec<Chapter> {
+        let chapters: Vec<Chapter> = video.chapters.as_deref().unwrap_or_default().iter().enumerate()
+            .filter_map(|(idx, c)| Some(Chapter {
+                title: c.title.clone().filter(|t| !t.trim().is_empty()).unwrap_or(format!("Chapter {}", idx + 1)),
+                start: c.start_time?,
+                end: c.end_time.unwrap_or(duration),
+            }))
+            .filter(|c| c.length() > 0.0)
+            .collect();
+
+        if !chapters.is_empty() {
+            return chapters;
+        }
+        return parse_description_chapters(video.description.as_deref().unwrap_or_default(), duration);
+    }
+
+    /// Reads a tracklist like `0:00 Intro` / `1. 03:25 - Song` from a description.
+    /// Like YouTube itself, the list has to start at 0:00 and have at least two ascending entries.
+    pub fn parse_description_chapters(description: &str, duration: f64) -> Vec<Chapter> {
+        let separators: &[char] = &[' ', '\t', '-', '\u{2013}', '\u{2014}', '|', ':', '.'];
+        let mut marks: Vec<(f64, String)> = Vec::new();
+
+        for line in description.lines() {
+            let stamp = line.split_whitespace()
+                .map(|t| t.trim_matches(|c: char| !c.is_ascii_digit()))
+                .find(|t| t.contains(':'))
+                .and_then(|t| Some((t, parse_timestamp(t)?)));
+            let (token, start) = match stamp {
+                Some(stamp) => stamp,
+                None => continue,
+            };
+
+            let title = line.replacen(token, "", 1).replace("[]", "").replace("()", "");
+            let mut title = title.trim_matches(separators).to_string();
+            // Drop list numbering such as "1." or "02)".
+            if let Some((num, rest)) = title.split_once(['.', ')']) {
+                if !num.is_empty() && num.chars().all(|c| c.is_ascii_digit()) {
+                    title = rest.trim_matches(separators).to_string();
+                }
+            }
+
+            let start = start as f64;
+            if start == 0.0 {
+                // A stray timestamp above the tracklist, start over.
+                marks.clear();
+            }
+            if marks.last().is_some_and(|(last, _)| start <= *last) || (duration > 0.0 && start >= duration) {
+                return Vec::new();
+            }
+            marks.push((start, title));
+        }
+
+        if marks.len() < 2 || marks[0].0 != 0.0 {
+            return Vec::new();
+        }
+
+        return marks.iter().enumerate().map(|(idx, (start, title))| Chapter {
+            title: if title.is_empty() { format!("Chapter {}", idx + 1) } else { title.clone() },
+            start: *start,
+            end: marks.get(idx + 1).map(|(next, _)| *next).unwrap_or(duration),
+        }).collect();
+    }
+
+    /// Parses any YouTube link shape (watch, youtu.be, shorts, embed, live,
+    /// music/mobile hosts) or a bare video ID.
+    pub fn parse_link(link: &str) -> Option<VideoLink> {
+        let link = link.trim();
+
+        if let Some(id) = VideoId::parse(link) {
+            return Some(VideoLink { id, start: None, list: None });
+        }
+
+        let url = if link.contains("://") {
+            Url::parse(link).ok()?
+        } else {
+            Url::parse(&format!("https://{}", link)).ok()?
+        };
+
+        let host = url.host_str()?.to_ascii_lowercase();
+        let host = host.strip_prefix("www.").unwrap_or(&host);
+
+        let query = |key: &str| url.query_pairs().find(|(k, _)| k == key).map(|(_, v)| v.into_owned());
+        let segments: Vec<&str> = url.path_segments()
+            .map(|s| s.filter(|x| !x.is_empty()).collect())
+            .unwrap_or_default();
+
+        let raw_id = match host {
+            "youtu.be" => segments.first().map(|x| x.to_string()),
+            "youtube.com" | "m.youtube.com" | "music.youtube.com" | "youtube-nocookie.com" => {
+                match segments.as_slice() {
+                    ["watch", ..] => query("v"),
+                    ["shorts" | "embed" | "live" | "v" | "e", id, ..] => Some(id.to_string()),
+                    _ => None,
+                }
+            },
+            _ => None,
+        }?;
+
+        let id = VideoId::parse(&raw_id)?;
+
+        let fragment_t = url.fragment()
+            .and_then(|f| f.strip_prefix("t="))
+            .map(|x| x.to_string());
+        let start = query("t").or(query("start")).or(fragment_t)
+            .and_then(|t| parse_timestamp(&t));
+
+        let list = query("list").and_then(|l| PlaylistId::parse(&l));
+
+        return Some(VideoLink { id, start, list });
+    }
+
+    /// Parses `90`, `90s`, `1m30s`, `1h2m3s` and `1:02:03` into seconds.
+    pub fn parse_timestamp(raw: &str) -> Option<u64> {
+        let raw = raw.trim();
+
+        if raw.is_empty() {
+            return None;
+        }
+
+        if raw.contains(':') {
+            let mut total: u64 = 0;
+            for part in raw.split(':') {
+                if part.is_empty() || !part.chars().all(|c| c.is_ascii_digit()) {
+                    return None;
+                }
+                total = total.checked_mul(60)?.checked_add(part.parse::<u64>().ok()?)?;
+            }
+            return Some(total);
+        }
+
+        if raw.chars().all(|c| c.is_ascii_digit()) {
+            return raw.parse().ok();
+        }
+
+        let mut total: u64 = 0;
+        let mut num = String::new();
+        for c in raw.chars() {
+            if c.is_ascii_digit() {
+                num.push(c);
+                continue;
+            }
+            let mult = match c {
+                'h' => 3600,
+                'm' => 60,
+                's' => 1,
+                _ => return None,
+            };
+            total = total.checked_add(num.parse::<u64>().ok()?.checked_mul(mult)?)?;
+            num.clear();
+        }
+
+        if !num.is_empty() {
+            return None;
+        }
+
+        return Some(total);
+    }
+
+    pub async fn download_audio(id: &VideoId, ytdl_path: &Path, download : Option<bool>) -> Option<SingleVideo> {
+        return download_audio_url(&id.watch_url(), id.as_str(), ytdl_path, download).await;
+    }
+
+    pub async fn download_audio_url(url: &str, key: &str, ytdl_path: &Path, download : Option<bool>) -> Option<SingleVideo> {
+        println!("Downloading video: {}", url);
+
+        let dl = download.unwrap_or_default();
+
+        let output = YoutubeDl::new(url)
+            .youtube_dl_path(ytdl_path)
+            .socket_timeout("15")
+            .extra_arg("--no-playlist")
+            .format("bestaudio")
+            .extract_audio(dl)
+            .output_template(format!("[{}].%(ext)s", key))
+            .download(dl)
+            .run_async().await;
+            
+        return match output {
+            Ok(v) => {
+                v.into_single_video()
+            },
+            Err(_) => {
+                None
+            },
+        }
+    }
+
+    pub async fn download_video(id: &VideoId, ytdl_path: &Path, download : Option<bool>) -> Option<SingleVideo> {
+        return download_video_url(&id.watch_url(), id.as_str(), ytdl_path, download).await;
+    }
+
+    pub async fn download_video_url(url: &str, key: &str, ytdl_path: &Path, download : Option<bool>) -> Option<SingleVideo> {
+        println!("Downloading video: {}", url);
+
+        let dl = download.unwrap_or_default();
+
+        let output = YoutubeDl::new(url)
+            .youtube_dl_path(ytdl_path)
+            .socket_timeout("15")
+            .extra_arg("--no-playlist")
+            .format("bestaudio+bestvideo")
+            .output_template(format!("[{}].%(ext)s", key))
+            .download(dl)
+            .run_async().await;
+            
+        return match output {
+            Ok(v) => {
+                v.into_single_video()
+            },
+            Err(_) => {
+                None
+            },
+        }
+    }
+
+    pub async fn get_metadata(id: &VideoId, ytdl_path: &Path, video: Option<bool>) -> Option<SingleVideo> {
+        return get_metadata_url(&id.watch_url(), ytdl_path, video).await;
+    }
+
+    pub async fn get_metadata_url(url: &str, ytdl_path: &Path, video: Option<bool>) -> Option<SingleVideo> {
+        let opt = if video.is_some_and(| x | x) { "bestaudio+bestvideo" } else { "bestaudio" };
+
+        return get_metadata_selector(url, ytdl_path, opt).await;
+    }
+
+    /// Metadata of `url` with the stream picked by an explicit yt-dlp format selector.
+    pub async fn get_metadata_selector(url: &str, ytdl_path: &Path, selector: &str) -> Option<SingleVideo> {
+        let output = YoutubeDl::new(url)
+            .youtube_dl_path(ytdl_path)
+            .socket_timeout("15")
+            .extra_arg("--no-playlist")
+            .format(selector)
+            .run_async().await;
+        
+        return match output {
+            Ok(v) => {
+                v.into_single_video()
+            },
+            Err(_) => {
+                None
+            },
+        }
+    }
+    
+    /// Runs a yt-dlp `ytsearchN:` query and returns the flat result entries.
+    pub async fn search(query: &str, count: usize, ytdl_path: &Path) -> Option<Vec<SingleVideo>> {
+        let options = SearchOptions::youtube(query).with_count(count);
+
+        let output = YoutubeDl::search_for(&options)
+            .youtube_dl_path(ytdl_path)
+            .socket_timeout("15")
+            .flat_playlist(true)
+            .run_async().await;
+
+        return match output {
+            Ok(v) => {
+                v.into_playlist().map(|p| p.entries.unwrap_or_default())
+            },
+            Err(_) => {
+                None
+            },
+        }
+    }
+
+    pub async fn search_media(query: &str, count: usize) -> Result<Vec<MediaMetadata>, DownloaderError> {
+        let _root: PathBuf = env::current_dir().unwrap();
+        let (ytdlp_path, _) = setup(&_root).unwrap();
+
+        if query.trim().is_empty() {
+            return Err(DownloaderError::InvalidInput("Empty search query".to_string()));
+        }
+
+        return match search(query.trim(), count, &ytdlp_path).await {
+            Some(entries) => {
+                let results: Vec<MediaMetadata> = entries.iter().map(MediaMetadata::from_single_video).collect();
+                results.iter().for_each(crate::thumbnails::remember_source);
+                Ok(results)
+            },
+            None => Err(DownloaderError::Upstream(format!("Search for {:?} failed", query))),
+        }
+    }
+
+    pub async fn get_playlist(list: &PlaylistId, ytdl_path: &Path) -> Option<Playlist> {
+        let url = list.playlist_url();
+
+        let output = YoutubeDl::new(&url)
+            .youtube_dl_path(ytdl_path)
+            .socket_timeout("15")
+            .flat_playlist(true)
+            .run_async().await;
+
+        return match output {
+            Ok(v) => {
+                v.into_playlist()
+            },
+            Err(_) => {
+                None
+            },
+        }
+    }
+    
+    /// Sidecar `<file>.json` next to a cached file, holding facts that are costly to recompute.
+    #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+    pub struct CacheMeta {
+        /// Source loudness measured for normalization.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub loudness: Option<LoudnessMeasurement>,
+        /// Loudness of the file itself, as measured for its ReplayGain tags.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub output_loudness: Option<LoudnessMeasurement>,
+        /// Silence cut from the start and end.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub silence: Option<SilenceTrim>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub duration: Option<f64>,
+    }
+
+    impl CacheMeta {
+        pub fn path(media: &Path) -> PathBuf {
+            let mut name = media.file_name().unwrap_or_default().to_os_string();
+            name.push(".json");
+            return media.with_file_name(name);
+        }
+
+        pub fn read(media: &Path) -> Option<CacheMeta> {
+            let raw = fs::read(CacheMeta::path(media)).ok()?;
+            return serde_json::from_slice(&raw).ok();
+        }
+
+        pub fn write(&self, media: &Path) -> Result<(), io::Error> {
+            let raw = serde_json::to_vec_pretty(self).map_err(io::Error::other)?;
+            return fs::write(CacheMeta::path(media), raw);
+        }
+    }
+
+    /// `<stem> rg.<ext>` next to a cached file, its ReplayGain tagged copy.
+    pub fn replaygain_path(media: &Path) -> PathBuf {
+        let mut name = media.file_stem().unwrap_or_default().to_os_string();
+        name.push(" rg");
+        if let Some(ext) = media.extension() {
+            name.push(".");
+            name.push(ext);
+        }
+        media.with_file_name(name)
+    }
+
+    /// Returns a copy of the cached `media` with ReplayGain track tags, creating it when missing.
+    /// `media` itself stays untouched since plain downloads are served from it too.
+    pub async fn ensure_replaygain(transcoder: &dyn Transcoder, media: &Path, format: AudioFormat, duration: f64) -> Result<PathBuf, DownloaderError> {
+        let tagged = replaygain_path(media);
+        if tagged.exists() && CacheMeta::read(&tagged).is_some_and(|m| m.output_loudness.is_some()) {
+            return Ok(tagged);
+        }
+
+        let mut meta = CacheMeta::read(media).unwrap_or_default();
+        let measured = match meta.output_loudness {
+            Some(measured) => measured,
+            None => {
+                let log = transcoder.run(&loudness::measure_job(media, &LoudnessTarget::REPLAYGAIN)).await?;
+                let measured = loudness::parse_measurement(&log)
+                    .ok_or_else(|| DownloaderError::Transcode("loudnorm did not report measurements".to_string()))?;
+                meta.output_loudness = Some(measured);
+                meta.duration = meta.duration.or(Some(duration));
+                meta.write(media)?;
+                measured
+            },
+        };
+
+        let mut partial = std::ffi::OsString::from(".");
+        partial.push(tagged.file_name().unwrap_or_default());
+        let partial = tagged.with_file_name(partial);
+        let res = fs::copy(media, &partial).map_err(DownloaderError::from)
+            .and_then(|_| write_replaygain(&partial, format, &ReplayGain::track(&measured)))
+            .and_then(|_| Ok(fs::rename(&partial, &tagged)?));
+        if res.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        res?;
+        meta.write(&tagged)?;
+
+        return Ok(tagged);
+    }
+
+    pub fn move_video_to_temp(root_dir : &Path, filename: &str) -> Result<PathBuf, io::Error> {
+        let temp_dir = root_dir.join("temp");
+
+        let filepath = root_dir.join(filename);
+
+        if !filepath.exists() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,format!("{} does not exist", filename)));
+        }
+        
+        fs::create_dir_all(&temp_dir)?;
+
+        return match fs::rename(filepath, temp_dir.join(filename)) {
+            Ok(_) => {
+                let p = temp_dir.join(filename);
+                println!("Successfully moved {} to {}", filename, p.to_str().unwrap());
+                Ok(p)
+            },
+            Err(e) => {
+                println!("Error: {}", e);
+                Err(e)
+            },
+        };
+    }
+
+    pub fn setup(root : &Path) -> Option<(PathBuf, PathBuf)> {
+        if let Some(proj_dirs) = directories::ProjectDirs::from("me", "lukasz26671", "r_webaudioprov") {
+            let dir = proj_dirs.data_dir();
+            let mut ytdlp: PathBuf = env::current_dir().unwrap();
+            ytdlp.extend(&["yt-dlp.exe"]);
+
+            fs::create_dir_all(dir).unwrap();
+            if !dir.join("yt-dlp.exe").exists() {
+                match fs::copy(ytdlp,dir.join("yt-dlp.exe").as_path()) {
+                    Ok(_) => {
+                        println!("Successfully copied");
+                    },
+                    Err(err) => {
+                        panic!("failed to copy, {}", err);
+                    },
+                }
+            }
+            let p = dir.join("yt-dlp.exe");
+            return Some((p, root.join("temp")));
+        }
+        return None;
+    }
+
+    /// Renders the thumbnail of `id` as `options` ask, keeping the result in the temp dir.
+    pub async fn dl_get_thumbnail(id: &VideoId, options: &ThumbnailOptions) -> Result<PathBuf, DownloaderError> {
+        let _root: PathBuf = env::current_dir().unwrap();
+        let (_, tmp_path) = setup(&_root).unwrap();
+
+        let path = tmp_path.join(options.cache_name(id.as_str()));
+        if path.exists() {
+            return Ok(path);
+        }
+
+        let url = match known_source(id.as_str()) {
+            Some(url) => url,
+            None => thumbnail_url(&get_metadata_resp(id).await?)?,
+        };
+        let bytes = fetch_url(&url).await?;
+        let rendered = tokio::task::spawn_blocking({
+            let options = *options;
+            move || render(&bytes, &options)
+        }).await.map_err(|e| DownloaderError::Io(io::Error::other(e)))??;
+
+        fs::create_dir_all(&tmp_path)?;
+        fs::write(&path, rendered)?;
+
+        return Ok(path);
+    }
+
+    /// Caption languages yt-dlp lists for `video`. `live_chat` is a replay of the chat, not captions.
+    pub fn subtitle_languages(video: &SingleVideo) -> SubtitleLanguages {
+        let manual = video.subtitles.as_ref()
+            .map(|s| s.iter().filter(|(l, t)| *l != "live_chat" && t.as_ref().is_some_and(|t| !t.is_empty())).map(|(l, _)| l.clone()).collect())
+            .unwrap_or_default();
+        let automatic = video.automatic_captions.as_ref()
+            .map(|s| s.iter().filter(|(_, t)| !t.is_empty()).map(|(l, _)| l.clone()).collect())
+            .unwrap_or_default();
+
+        return SubtitleLanguages { manual, automatic };
+    }
+
+    pub async fn get_subtitle_languages(id: &VideoId) -> Option<SubtitleLanguages> {
+        let _root: PathBuf = env::current_dir().unwrap();
+        let (ytdlp_path, _) = setup(&_root)?;
+
+        return get_metadata_url(&id.watch_url(), &ytdlp_path, None).await.map(|v| subtitle_languages(&v));
+    }
+
+    /// Captions of `id` in `lang`, converted to `format`. Uploaded captions win; YouTube's
+    /// automatic ones are used only when `auto` allows it.
+    pub async fn dl_get_subtitles(id: &VideoId, lang: &str, auto: bool, format: SubtitleFormat) -> Result<PathBuf, DownloaderError> {
+        let _root: PathBuf = env::current_dir().unwrap();
+        let (ytdlp_path, tmp_path) = setup(&_root).unwrap();
+        let url = id.watch_url();
+
+        let vmetadata = match get_metadata_url(&url, &ytdlp_path, None).await {
+            Some(vmetadata) => vmetadata,
+            None => return Err(DownloaderError::NotFound(format!("{} not found", url))),
+        };
+        let (track, automatic) = pick_captions(&subtitle_languages(&vmetadata), lang, auto)?;
+
+        let key = media_key(&vmetadata);
+        let kind = if automatic { " auto" } else { "" };
+        let fname = format!("{} [{}] {}{}.{}", sanitize_title(&vmetadata.title), key, track, kind, format.extension());
+        let path = tmp_path.join(&fname);
+        if path.exists() {
+            println!("File {} found in storage", path.to_str().unwrap());
+
+            return Ok(path);
+        }
+
+        let cues = fetch_captions(&url, &key, &ytdlp_path, &track, automatic).await?;
+
+        fs::create_dir_all(&tmp_path)?;
+        fs::write(&path, format.render(&cues))?;
+
+        return Ok(path);
+    }
+
+    /// The caption track for `lang`, preferring uploaded captions over automatic ones,
+    /// which are only considered when `auto` is set. Returns the track and whether it is automatic.
+    pub fn pick_captions(available: &SubtitleLanguages, lang: &str, auto: bool) -> Result<(String, bool), DownloaderError> {
+        if let Some(track) = subtitles::pick_language(&available.manual, lang) {
+            return Ok((track, false));
+        }
+        if let Some(track) = subtitles::pick_language(&available.automatic, lang).filter(|_| auto) {
+            return Ok((track, true));
+        }
+
+        let listed = if auto { [available.manual.clone(), available.automatic.clone()].concat() } else { available.manual.clone() };
+        return Err(DownloaderError::NotFound(format!("No {} captions, available: {}", lang, listed.join(", "))));
+    }
+
+    /// Downloads one caption track of `url` with yt-dlp and parses it.
+    pub async fn fetch_captions(url: &str, key: &str, ytdl_path: &Path, track: &str, automatic: bool) -> Result<Vec<Cue>, DownloaderError> {
+        let _root: PathBuf = env::current_dir().unwrap();
+
+        println!("Downloading {} captions of {}", track, url);
+        // Kept apart from media downloads of the same video running at the same time.
+        let raw_key = format!("{} subs", key);
+        let template = format!("[{}].%(ext)s", raw_key);
+        let output = tokio::process::Command::new(ytdl_path)
+            .args(["--skip-download", if automatic { "--write-auto-subs" } else { "--write-subs" }])
+            .args(["--sub-langs", track, "--sub-format", "vtt/srt/best", "-o", &template, "--socket-timeout", "15", "--no-playlist", url])
+            .stdout(Stdio::null())
+            .output().await
+            .map_err(|e| DownloaderError::Upstream(format!("could not start yt-dlp: {}", e)))?;
+        if !output.status.success() {
+            return Err(DownloaderError::Upstream(format!("yt-dlp exited with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim())));
+        }
+
+        let raw_path = match find_download(&_root, &raw_key) {
+            Some(raw_path) => raw_path,
+            None => return Err(DownloaderError::Upstream(format!("Captions of {} could not be downloaded", url))),
+        };
+        let raw = fs::read_to_string(&raw_path);
+        fs::remove_file(&raw_path)?;
+
+        let mut cues = subtitles::parse(&raw?);
+        if automatic {
+            cues = subtitles::dedupe_rolling(cues);
+        }
+        if cues.is_empty() {
+            return Err(DownloaderError::Upstream(format!("Captions of {} are empty or unreadable", url)));
+        }
+
+        return Ok(cues);
+    }
+
+    /// Writes the captions `options.subtitles` asks for as SRT files next to the download,
+    /// shifted to the clip. Languages without captions are skipped, the video matters more.
+    pub async fn fetch_subtitle_tracks(url: &str, key: &str, ytdl_path: &Path, video: &SingleVideo, options: &VideoOptions, duration: f64) -> Vec<SubtitleTrack> {
+        let _root: PathBuf = env::current_dir().unwrap();
+        let available = subtitle_languages(video);
+        let offset = options.clip.map(|c| c.start as f64).unwrap_or_default();
+        let mut tracks = Vec::new();
+
+        for lang in &options.subtitles {
+            let res = match pick_captions(&available, lang, true) {
+                Ok((track, automatic)) => fetch_captions(url, key, ytdl_path, &track, automatic).await.map(|cues| (track, automatic, cues)),
+                Err(e) => Err(e),
+            };
+            let (track, automatic, cues) = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    println!("Skipping {} subtitles: {}", lang, e);
+                    continue;
+                }
+            };
+
+            let path = _root.join(format!("[{} subs] {}.srt", key, track));
+            let cues = subtitles::shift(&cues, offset, duration);
+            if let Err(e) = fs::write(&path, subtitles::to_srt(&cues)) {
+                println!("Skipping {} subtitles: {}", lang, e);
+                continue;
+            }
+            tracks.push(SubtitleTrack { path, language: track, automatic });
+        }
+
+        return tracks;
+    }
+
+    pub async fn get_metadata_resp(video_id: &VideoId) -> Result<MediaMetadata, DownloaderError> {
+        
+        let id = Id::from_raw(video_id.as_str())?;
+
+        let descrambler = VideoFetcher::from_id(id.into_owned())?.fetch().await?;
+
+        let info = descrambler.video_info();
+        let details = descrambler.video_details();
+        
+        let result = MediaMetadata {
+            title: (details.title.to_string()),
+            author: (details.author.to_string()),
+            short_desc: (details.short_description.to_string()),
+            id: (details.video_id.to_string()),
+            age_restricted: info.is_age_restricted,
+            is_private: details.is_private,
+            length: details.length_seconds,
+            thumbnails: Some(details.thumbnails.to_vec()),
+            subtitles: None,
+        };
+
+        Ok(result)
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug)]
+    pub struct MediaMetadata {
+        pub title: String,
+        pub author: String,
+        pub short_desc: String,
+        pub id: String,
+        pub length: u64,
+        pub age_restricted: bool,
+        pub is_private: bool,
+        pub thumbnails: Option<Vec<Thumbnail>>,
+        /// Caption languages, only known when yt-dlp was asked.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub subtitles: Option<SubtitleLanguages>,
+    }
+
+    impl MediaMetadata {
+        /// Widest thumbnail, skipping `maxres` ones which YouTube often lacks.
+        pub fn best_thumbnail(&self) -> Option<&Thumbnail> {
+            return self.thumbnails.as_ref()
+                .and_then(|t| t.iter().filter(|x| !x.url.contains("maxres")).max_by_key(|x| x.width));
+        }
+
+        pub fn from_single_video(video: &SingleVideo) -> MediaMetadata {
+            let thumbnails = video.thumbnails.as_ref().map(|t| t.iter()
+                .filter_map(|x| Some(Thumbnail {
+                    width: x.width.unwrap_or_default() as u64,
+                    height: x.height.unwrap_or_default() as u64,
+                    url: x.url.clone()?,
+                }))
+                .collect());
+
+            return MediaMetadata {
+                title: video.title.clone(),
+                author: video.uploader.clone().or(video.channel.clone()).unwrap_or_default(),
+                short_desc: video.description.clone().unwrap_or_default(),
+                id: video.id.clone(),
+                length: video.duration.as_ref().and_then(|d| d.as_f64()).unwrap_or_default() as u64,
+                age_restricted: video.age_limit.unwrap_or_default() >= 18,
+                is_private: false,
+                thumbnails,
+                subtitles: Some(subtitle_languages(video)),
+            };
+        }
+    }
+
+}
+
+pub mod tagging {
+    use crate::downloader::{AudioFormat, DownloaderError, MediaMetadata};
+    use crate::thumbnails::{fetch_thumbnail, render, ThumbnailFormat, ThumbnailOptions};
+    use lofty::config::WriteOptions;
+    use lofty::id3::v2::{CommentFrame, ExtendedTextFrame, ExtendedUrlFrame, Frame, FrameId, Id3v2Tag, TextInformationFrame};
+    use crate::loudness::ReplayGain;
+    use lofty::config::ParseOptions;
+    use lofty::file::AudioFile;
+    use lofty::flac::FlacFile;
+    use lofty::iff::wav::WavFile;
+    use lofty::mp4::{Atom, AtomData, AtomIdent, Ilst, Mp4File};
+    use lofty::mpeg::MpegFile;
+    use lofty::ogg::{OggPictureStorage, OpusFile, VorbisComments, VorbisFile};
+    use lofty::picture::{MimeType, Picture, PictureType};
+    use lofty::tag::{Accessor, TagExt};
+    use lofty::TextEncoding;
+    use std::fs::File;
+    use std::path::Path;
+    use youtube_dl::SingleVideo;
+
+    /// Largest edge of an embedded cover, players rarely show more.
+    pub const COVER_SIZE: u32 = 800;
+
+    /// Tag values for one downloaded track, independent of the container format.
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct TrackTags {
+        pub title: String,
+        pub artist: String,
+        pub album: Option<String>,
+        /// Release or upload date as `YYYY-MM-DD`.
+        pub date: Option<String>,
+        pub source_url: Option<String>,
+        pub video_id: String,
+        /// Track number and total, set for parts of a split video.
+        pub track: Option<(u32, u32)>,
+        /// Square JPEG front cover.
+        pub cover: Option<Vec<u8>>,
+    }
+
+    impl TrackTags {
+        /// Prefers the music metadata yt-dlp extracts (track, artist) over the video's title and uploader.
+        pub fn from_video(video: &SingleVideo, metadata: &MediaMetadata) -> TrackTags {
+            let date = video.release_date.as_deref()
+                .or(video.upload_date.as_deref())
+                .and_then(format_date);
+
+            return TrackTags {
+                title: video.track.clone().unwrap_or(metadata.title.clone()),
+                artist: video.artist.clone().unwrap_or(metadata.author.clone()),
+                album: video.album.clone(),
+                date,
+                source_url: video.webpage_url.clone(),
+                video_id: metadata.id.clone(),
+                track: None,
+                cover: None,
+            };
+        }
+    }
+
+    /// Turns yt-dlp's `YYYYMMDD` into an ISO 8601 date.
+    pub fn format_date(raw: &str) -> Option<String> {
+        if raw.len() != 8 || !raw.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+
+        return Some(format!("{}-{}-{}", &raw[..4], &raw[4..6], &raw[6..]));
+    }
+
+    /// Crops an image to its centered square and re-encodes it as JPEG, at most `COVER_SIZE` wide.
+    pub fn square_cover(bytes: &[u8]) -> Result<Vec<u8>, DownloaderError> {
+        return render(bytes, &ThumbnailOptions { size: Some(COVER_SIZE), square: true, format: ThumbnailFormat::Jpeg })
+            .map_err(|e| DownloaderError::Tag(format!("cover: {}", e)));
+    }
+
+    /// Downloads the thumbnail `html_get_info_id` would show and squares it.
+    /// Covers are optional, so failures are logged and yield `None`.
+    pub async fn fetch_cover(metadata: &MediaMetadata) -> Option<Vec<u8>> {
+        let bytes = match fetch_thumbnail(metadata).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                println!("Could not fetch cover: {}", e);
+                return None;
+            }
+        };
+
+        return match square_cover(&bytes) {
+            Ok(cover) => Some(cover),
+            Err(e) => {
+                println!("{}", e);
+                None
+            }
+        }
+    }
+
+    /// ID3v2.4 tag for `tags`.
+    pub fn id3_tag(tags: &TrackTags) -> Id3v2Tag {
+        let mut tag = Id3v2Tag::new();
+        tag.set_title(tags.title.clone());
+        tag.set_artist(tags.artist.clone());
+        if let Some(album) = &tags.album {
+            tag.set_album(album.clone());
+        }
+        if let Some((number, total)) = tags.track {
+            tag.set_track(number);
+            tag.set_track_total(total);
+        }
+        if let Some(date) = &tags.date {
+            tag.insert(Frame::Text(TextInformationFrame::new(FrameId::Valid("TDRC".into()), TextEncoding::UTF8, date.clone())));
+        }
+        if let Some(url) = &tags.source_url {
+            tag.insert(Frame::UserUrl(ExtendedUrlFrame::new(TextEncoding::UTF8, "Source".to_string(), url.clone())));
+            tag.insert(Frame::Comment(CommentFrame::new(TextEncoding::UTF8, *b"eng", "".to_string(), url.clone())));
+        }
+        tag.insert(Frame::UserText(ExtendedTextFrame::new(TextEncoding::UTF8, "VIDEO_ID".to_string(), tags.video_id.clone())));
+        if let Some(cover) = &tags.cover {
+            tag.insert_picture(Picture::new_unchecked(PictureType::CoverFront, Some(MimeType::Jpeg), None, cover.clone()));
+        }
+
+        return tag;
+    }
+
+    /// Vorbis comments for `tags`. In Ogg/Opus the cover becomes a METADATA_BLOCK_PICTURE
+    /// comment, FLAC stores the same structure in its own PICTURE block.
+    pub fn vorbis_comments(tags: &TrackTags) -> Result<VorbisComments, DownloaderError> {
+        let mut comments = VorbisComments::new();
+        comments.set_title(tags.title.clone());
+        comments.set_artist(tags.artist.clone());
+        if let Some(album) = &tags.album {
+            comments.set_album(album.clone());
+        }
+        if let Some((number, total)) = tags.track {
+            comments.set_track(number);
+            comments.set_track_total(total);
+        }
+        if let Some(date) = &tags.date {
+            comments.insert("DATE".to_string(), date.clone());
+        }
+        if let Some(url) = &tags.source_url {
+            comments.insert("WEBSITE".to_string(), url.clone());
+            comments.insert("COMMENT".to_string(), url.clone());
+        }
+        comments.insert("VIDEO_ID".to_string(), tags.video_id.clone());
+        if let Some(cover) = &tags.cover {
+            comments.insert_picture(Picture::new_unchecked(PictureType::CoverFront, Some(MimeType::Jpeg), None, cover.clone()), None)
+                .map_err(|e| DownloaderError::Tag(e.to_string()))?;
+        }
+
+        return Ok(comments);
+    }
+
+    /// iTunes-style `ilst` atoms for `tags`, with the cover in `covr`.
+    pub fn ilst(tags: &TrackTags) -> Ilst {
+        let mut ilst = Ilst::default();
+        ilst.set_title(tags.title.clone());
+        ilst.set_artist(tags.artist.clone());
+        if let Some(album) = &tags.album {
+            ilst.set_album(album.clone());
+        }
+        if let Some((number, total)) = tags.track {
+            ilst.set_track(number);
+            ilst.set_track_total(total);
+        }
+        if let Some(date) = &tags.date {
+            ilst.insert(Atom::new(AtomIdent::Fourcc(*b"\xa9day"), AtomData::UTF8(date.clone())));
+        }
+        if let Some(url) = &tags.source_url {
+            ilst.insert(Atom::new(AtomIdent::Fourcc(*b"\xa9cmt"), AtomData::UTF8(url.clone())));
+        }
+        ilst.insert(Atom::new(
+            AtomIdent::Freeform { mean: "com.apple.iTunes".into(), name: "VIDEO_ID".into() },
+            AtomData::UTF8(tags.video_id.clone()),
+        ));
+        if let Some(cover) = &tags.cover {
+            ilst.insert_picture(Picture::new_unchecked(PictureType::CoverFront, Some(MimeType::Jpeg), None, cover.clone()));
+        }
+
+        return ilst;
+    }
+
+    /// Writes `tags` into the file at `path` using the tagging system of `format`,
+    /// replacing any tag the file already carries.
+    pub fn write_tags(path: &Path, format: AudioFormat, tags: &TrackTags) -> Result<(), DownloaderError> {
+        let res = match format {
+            // WAV gets an `id3 ` chunk, which is what most players read there.
+            AudioFormat::Mp3 | AudioFormat::Wav => id3_tag(tags).save_to_path(path, WriteOptions::default()),
+            AudioFormat::Flac | AudioFormat::Ogg | AudioFormat::Opus => vorbis_comments(tags)?.save_to_path(path, WriteOptions::default()),
+            AudioFormat::M4a => ilst(tags).save_to_path(path, WriteOptions::default()),
+        };
+
+        return res.map_err(|e| DownloaderError::Tag(e.to_string()));
+    }
+
+    pub fn replaygain_id3(tag: &mut Id3v2Tag, gain: &ReplayGain) {
+        for (key, value) in gain.tag_values() {
+            tag.insert(Frame::UserText(ExtendedTextFrame::new(TextEncoding::UTF8, key.to_string(), value)));
+        }
+    }
+
+    /// Opus players apply `R128_*` gains, the `REPLAYGAIN_*` keys are for everything else.
+    pub fn replaygain_vorbis(comments: &mut VorbisComments, gain: &ReplayGain, opus: bool) {
+        let values = if opus { gain.r128_values() } else { gain.tag_values() };
+        for (key, value) in values {
+            comments.insert(key.to_string(), value);
+        }
+    }
+
+    pub fn replaygain_ilst(ilst: &mut Ilst, gain: &ReplayGain) {
+        for (key, value) in gain.tag_values() {
+            ilst.insert(Atom::new(
+                AtomIdent::Freeform { mean: "com.apple.iTunes".into(), name: key.into() },
+                AtomData::UTF8(value),
+            ));
+        }
+    }
+
+    /// Adds ReplayGain values to the tag `path` already carries, leaving the audio untouched.
+    pub fn write_replaygain(path: &Path, format: AudioFormat, gain: &ReplayGain) -> Result<(), DownloaderError> {
+        let mut file = File::open(path)?;
+        let opts = ParseOptions::new().read_properties(false);
+
+        let res = match format {
+            AudioFormat::Mp3 => MpegFile::read_from(&mut file, opts).and_then(|f| {
+                let mut tag = f.id3v2().cloned().unwrap_or_default();
+                replaygain_id3(&mut tag, gain);
+                tag.save_to_path(path, WriteOptions::default())
+            }),
+            AudioFormat::Wav => WavFile::read_from(&mut file, opts).and_then(|f| {
+                let mut tag = f.id3v2().cloned().unwrap_or_default();
+                replaygain_id3(&mut tag, gain);
+                tag.save_to_path(path, WriteOptions::default())
+            }),
+            AudioFormat::Flac => FlacFile::read_from(&mut file, opts).and_then(|f| {
+                let mut comments = f.vorbis_comments().cloned().unwrap_or_default();
+                // FLAC keeps covers in PICTURE blocks, which are rewritten from the comments.
+                for (picture, info) in f.pictures() {
+                    comments.insert_picture(picture.clone(), Some(*info))?;
+                }
+                replaygain_vorbis(&mut comments, gain, false);
+                comments.save_to_path(path, WriteOptions::default())
+            }),
+            AudioFormat::Ogg => VorbisFile::read_from(&mut file, opts).and_then(|f| {
+                let mut comments = f.vorbis_comments().clone();
+                replaygain_vorbis(&mut comments, gain, false);
+                comments.save_to_path(path, WriteOptions::default())
+            }),
+            AudioFormat::Opus => OpusFile::read_from(&mut file, opts).and_then(|f| {
+                let mut comments = f.vorbis_comments().clone();
+                replaygain_vorbis(&mut comments, gain, true);
+                comments.save_to_path(path, WriteOptions::default())
+            }),
+            AudioFormat::M4a => Mp4File::read_from(&mut file, opts).and_then(|f| {
+                let mut ilst = f.ilst().cloned().unwrap_or_default();
+                replaygain_ilst(&mut ilst, gain);
+                ilst.save_to_path(path, WriteOptions::default())
+            }),
+        };
+
+        return res.map_err(|e| DownloaderError::Tag(e.to_string()));
+    }
+}
+
+pub mod thumbnails {
+    use crate::downloader::{DownloaderError, MediaMetadata};
+    use image::imageops::FilterType;
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::sync::{Mutex, OnceLock};
+    use std::time::Duration;
+
+    /// Largest edge a thumbnail can be asked for, YouTube rarely has more.
+    pub const MAX_SIZE: u32 = 1280;
+
+    /// Thumbnail URLs kept from search results before the map is cleared.
+    const MAX_SOURCES: usize = 1024;
+
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    static SOURCES: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+    /// Process-wide client for image downloads, so a stalled CDN can't hold a request forever.
+    pub fn client() -> &'static reqwest::Client {
+        CLIENT.get_or_init(|| reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(30))
+            .build()
+            .expect("Could not build HTTP client."))
+    }
+
+    /// Remembers the thumbnail `metadata` points at, so serving it later needs no page fetch.
+    pub fn remember_source(metadata: &MediaMetadata) {
+        if let Some(thumbnail) = metadata.best_thumbnail() {
+            let mut sources = SOURCES.get_or_init(|| Mutex::new(HashMap::new())).lock().unwrap();
+            if sources.len() >= MAX_SOURCES {
+                sources.clear();
+            }
+            sources.insert(metadata.id.clone(), thumbnail.url.clone());
+        }
+    }
+
+    /// Thumbnail URL remembered for `id`, if it showed up in a recent search.
+    pub fn known_source(id: &str) -> Option<String> {
+        SOURCES.get()?.lock().unwrap().get(id).cloned()
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum ThumbnailFormat {
+        #[default]
+        Jpeg,
+        Webp,
+    }
+
+    impl ThumbnailFormat {
+        pub fn parse(raw: &str) -> Option<ThumbnailFormat> {
+            return match raw.to_ascii_lowercase().as_str() {
+                "jpeg" | "jpg" => Some(ThumbnailFormat::Jpeg),
+                "webp" => Some(ThumbnailFormat::Webp),
+                _ => None,
+            }
+        }
+
+        pub fn extension(&self) -> &'static str {
+            return match self {
+                ThumbnailFormat::Jpeg => "jpg",
+                ThumbnailFormat::Webp => "webp",
+            }
+        }
+
+        pub fn content_type(&self) -> &'static str {
+            return match self {
+                ThumbnailFormat::Jpeg => "image/jpeg",
+                ThumbnailFormat::Webp => "image/webp",
+            }
+        }
+    }
+
+    /// How a thumbnail is cut and encoded.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct ThumbnailOptions {
+        /// Longest edge in pixels, images are only ever scaled down.
+        pub size: Option<u32>,
+        /// Crop to the centered square, as album art.
+        pub square: bool,
+        pub format: ThumbnailFormat,
+    }
+
+    impl ThumbnailOptions {
+        /// File name of the rendered thumbnail of `id` in the cache.
+        pub fn cache_name(&self, id: &str) -> String {
+            let size = self.size.map(|s| format!(" {}px", s)).unwrap_or_default();
+            let square = if self.square { " square" } else { "" };
+            return format!("{} thumbnail{}{}.{}", id, size, square, self.format.extension());
+        }
+    }
+
+    /// URL of the thumbnail `MediaMetadata::best_thumbnail` picks.
+    pub fn thumbnail_url(metadata: &MediaMetadata) -> Result<String, DownloaderError> {
+        match metadata.best_thumbnail() {
+            Some(thumbnail) => Ok(thumbnail.url.clone()),
+            None => Err(DownloaderError::NotFound(format!("{} has no thumbnail", metadata.id))),
+        }
+    }
+
+    /// Downloads the thumbnail `MediaMetadata::best_thumbnail` picks.
+    pub async fn fetch_thumbnail(metadata: &MediaMetadata) -> Result<Vec<u8>, DownloaderError> {
+        fetch_url(&thumbnail_url(metadata)?).await
+    }
+
+    pub async fn fetch_url(url: &str) -> Result<Vec<u8>, DownloaderError> {
+        let resp = client().get(url).send().await.and_then(|r| r.error_for_status())
+            .map_err(|e| DownloaderError::Upstream(format!("Could not fetch thumbnail {}: {}", url, e)))?;
+        let bytes = resp.bytes().await
+            .map_err(|e| DownloaderError::Upstream(format!("Could not fetch thumbnail {}: {}", url, e)))?;
+
+        return Ok(bytes.to_vec());
+    }
+
+    /// Crops and scales an image as `options` ask and re-encodes it.
+    pub fn render(bytes: &[u8], options: &ThumbnailOptions) -> Result<Vec<u8>, DownloaderError> {
+        let mut img = image::load_from_memory(bytes)
+            .map_err(|e| DownloaderError::Upstream(format!("unreadable image: {}", e)))?;
+
+        if options.square {
+            let side = img.width().min(img.height());
+            img = img.crop_imm((img.width() - side) / 2, (img.height() - side) / 2, side, side);
+        }
+        if let Some(size) = options.size.filter(|s| *s < img.width().max(img.height())) {
+            img = img.resize(size, size, FilterType::Lanczos3);
+        }
+
+        let format = match options.format {
+            ThumbnailFormat::Jpeg => image::ImageFormat::Jpeg,
+            ThumbnailFormat::Webp => image::ImageFormat::WebP,
+        };
+        let mut out = Cursor::new(Vec::new());
+        img.to_rgb8().write_to(&mut out, format)
+            .map_err(|e| DownloaderError::Io(std::io::Error::other(format!("could not encode image: {}", e))))?;
+
+        return Ok(out.into_inner());
+    }
+}
+
+pub mod subtitles {
+    use serde::{Deserialize, Serialize};
+    use std::path::PathBuf;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum SubtitleFormat {
+        #[default]
+        Srt,
+        Vtt,
+        /// Plain transcript, one cue per line.
+        Txt,
+    }
+
+    impl SubtitleFormat {
+        pub fn parse(raw: &str) -> Option<SubtitleFormat> {
+            return match raw.to_ascii_lowercase().as_str() {
+                "srt" => Some(SubtitleFormat::Srt),
+                "vtt" | "webvtt" => Some(SubtitleFormat::Vtt),
+                "txt" | "text" => Some(SubtitleFormat::Txt),
+                _ => None,
+            }
+        }
+
+        pub fn extension(&self) -> &'static str {
+            return match self {
+                SubtitleFormat::Srt => "srt",
+                SubtitleFormat::Vtt => "vtt",
+                SubtitleFormat::Txt => "txt",
+            }
+        }
+
+        pub fn content_type(&self) -> &'static str {
+            return match self {
+                SubtitleFormat::Srt => "application/x-subrip; charset=utf-8",
+                SubtitleFormat::Vtt => "text/vtt; charset=utf-8",
+                SubtitleFormat::Txt => "text/plain; charset=utf-8",
+            }
+        }
+
+        pub fn render(&self, cues: &[Cue]) -> String {
+            return match self {
+                SubtitleFormat::Srt => to_srt(cues),
+                SubtitleFormat::Vtt => to_vtt(cues),
+                SubtitleFormat::Txt => to_txt(cues),
+            }
+        }
+    }
+
+    /// Caption languages of a video, as yt-dlp names them.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct SubtitleLanguages {
+        pub manual: Vec<String>,
+        /// YouTube's speech recognition and its machine translations.
+        pub automatic: Vec<String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Cue {
+        pub start: f64,
+        pub end: f64,
+        /// Plain text without markup, lines separated by `\n`.
+        pub text: String,
+    }
+
+    /// `[hh:]mm:ss.mmm`, with `,` accepted as SRT writes it.
+    fn parse_time(raw: &str) -> Option<f64> {
+        let mut total = 0.0;
+        for part in raw.trim().replace(',', ".").split(':') {
+            total = total * 60.0 + part.parse::<f64>().ok()?;
+        }
+        return Some(total);
+    }
+
+    /// Drops `<c>`, `<i>`, inline `<00:00:01.000>` timestamps and other tags, then decodes entities.
+    fn strip_markup(line: &str) -> String {
+        let mut out = String::new();
+        let mut in_tag = false;
+        for c in line.chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' if in_tag => in_tag = false,
+                _ if !in_tag => out.push(c),
+                _ => {},
+            }
+        }
+        return out.replace("&nbsp;", " ").replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&").trim().to_string();
+    }
+
+    /// Reads WebVTT or SRT. Blocks without a timing line (header, NOTE, STYLE) are skipped.
+    pub fn parse(raw: &str) -> Vec<Cue> {
+        let raw = raw.replace("\r\n", "\n");
+        let mut cues = Vec::new();
+
+        for block in raw.split("\n\n") {
+            let mut lines = block.lines().skip_while(|l| !l.contains("-->"));
+            let timing = match lines.next() {
+                Some(timing) => timing,
+                None => continue,
+            };
+            let (start, rest) = match timing.split_once("-->") {
+                Some(parts) => parts,
+                None => continue,
+            };
+            // Cue settings such as `align:start position:0%` follow the end time.
+            let end = rest.split_whitespace().next().unwrap_or_default();
+            let (start, end) = match (parse_time(start), parse_time(end)) {
+                (Some(start), Some(end)) => (start, end),
+                _ => continue,
+            };
+
+            let text: Vec<String> = lines.map(strip_markup).filter(|l| !l.is_empty()).collect();
+            if !text.is_empty() {
+                cues.push(Cue { start, end, text: text.join("\n") });
+            }
+        }
+        return cues;
+    }
+
+    /// Automatic captions roll: every cue repeats the line before it. Keeps only new lines.
+    pub fn dedupe_rolling(cues: Vec<Cue>) -> Vec<Cue> {
+        let mut out: Vec<Cue> = Vec::new();
+        let mut last_line: Option<String> = None;
+
+        for cue in cues {
+            let mut lines: Vec<&str> = cue.text.lines().collect();
+            while !lines.is_empty() && last_line.as_deref() == Some(lines[0]) {
+                lines.remove(0);
+            }
+            if lines.is_empty() {
+                continue;
+            }
+            last_line = lines.last().map(|l| l.to_string());
+            out.push(Cue { text: lines.join("\n"), ..cue });
+        }
+        return out;
+    }
+
+    fn timestamp(seconds: f64, separator: char) -> String {
+        let ms = (seconds.max(0.0) * 1000.0).round() as u64;
+        return format!("{:02}:{:02}:{:02}{}{:03}", ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, separator, ms % 1000);
+    }
+
+    pub fn to_srt(cues: &[Cue]) -> String {
+        return cues.iter().enumerate()
+            .map(|(idx, c)| format!("{}\n{} --> {}\n{}\n\n", idx + 1, timestamp(c.start, ','), timestamp(c.end, ','), c.text))
+            .collect();
+    }
+
+    pub fn to_vtt(cues: &[Cue]) -> String {
+        let mut out = "WEBVTT\n\n".to_string();
+        for c in cues {
+            let text = c.text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+            out.push_str(&format!("{} --> {}\n{}\n\n", timestamp(c.start, '.'), timestamp(c.end, '.'), text));
+        }
+        return out;
+    }
+
+    pub fn to_txt(cues: &[Cue]) -> String {
+        return cues.iter().map(|c| format!("{}\n", c.text.replace('\n', " "))).collect();
+    }
+
+    /// Moves cues to a clip starting at `offset` and `length` seconds long, dropping those outside it.
+    /// A `length` of zero or less means the length is unknown and keeps every cue after `offset`.
+    pub fn shift(cues: &[Cue], offset: f64, length: f64) -> Vec<Cue> {
+        let length = if length > 0.0 { length } else { f64::INFINITY };
+
+        return cues.iter()
+            .map(|c| Cue { start: (c.start - offset).max(0.0), end: (c.end - offset).min(length), text: c.text.clone() })
+            .filter(|c| c.end > c.start)
+            .collect();
+    }
+
+    /// An SRT file to mux into a video as a soft subtitle track.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct SubtitleTrack {
+        pub path: PathBuf,
+        /// yt-dlp's language code, e.g. `en` or `pt-BR`.
+        pub language: String,
+        pub automatic: bool,
+    }
+
+    impl SubtitleTrack {
+        /// Name players show for the track.
+        pub fn title(&self) -> String {
+            return if self.automatic { format!("{} (auto-generated)", self.language) } else { self.language.clone() };
+        }
+    }
+
+    /// ISO 639-2 code for the `language` stream tag, which MP4 requires and Matroska prefers.
+    pub fn iso639_2(lang: &str) -> String {
+        let base = lang.split(['-', '_']).next().unwrap_or(lang).to_ascii_lowercase();
+        let code = match base.as_str() {
+            "en" => "eng", "de" => "deu", "fr" => "fra", "es" => "spa", "it" => "ita", "pt" => "por",
+            "nl" => "nld", "pl" => "pol", "ru" => "rus", "uk" => "ukr", "cs" => "ces", "sk" => "slk",
+            "sv" => "swe", "da" => "dan", "no" | "nb" => "nor", "fi" => "fin", "tr" => "tur", "el" => "ell",
+            "hu" => "hun", "ro" => "ron", "ja" => "jpn", "ko" => "kor", "zh" => "zho", "ar" => "ara",
+            "he" | "iw" => "heb", "hi" => "hin", "id" => "ind", "vi" => "vie", "th" => "tha",
+            _ if base.len() == 3 => return base,
+            _ => "und",
+        };
+        return code.to_string();
+    }
+
+    /// `lang` itself, or the first track of the same base language (`en` for `en-US`).
+    pub fn pick_language(available: &[String], lang: &str) -> Option<String> {
+        let lang = lang.to_ascii_lowercase();
+
+        return available.iter().find(|l| l.to_ascii_lowercase() == lang)
+            .or_else(|| available.iter().find(|l| l.to_ascii_lowercase().split('-').next() == Some(lang.as_str())))
+            .cloned();
+    }
+}
+
+pub mod loudness {
+    use crate::transcoder::TranscodeJob;
+    use serde::{Deserialize, Serialize};
+    use std::path::Path;
+
+    /// EBU R128 target for ffmpeg's `loudnorm`.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct LoudnessTarget {
+        /// Integrated loudness in LUFS.
+        pub integrated: f64,
+        /// Maximum true peak in dBTP.
+        pub true_peak: f64,
+        /// Loudness range in LU.
+        pub lra: f64,
+    }
+
+    /// What the first `loudnorm` pass reports about the source.
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+    pub struct LoudnessMeasurement {
+        pub input_i: f64,
+        pub input_tp: f64,
+        pub input_lra: f64,
+        pub input_thresh: f64,
+        pub target_offset: f64,
+    }
+
+    /// Reference loudness of ReplayGain 2.0.
+    pub const REPLAYGAIN_REFERENCE_LUFS: f64 = -18.0;
+
+    /// Reference loudness of Opus' R128 gain tags (RFC 7845).
+    pub const R128_REFERENCE_LUFS: f64 = -23.0;
+
+    impl LoudnessTarget {
+        /// Target for measuring only, the values don't change what `loudnorm` reports.
+        pub const REPLAYGAIN: LoudnessTarget = LoudnessTarget { integrated: REPLAYGAIN_REFERENCE_LUFS, true_peak: -1.0, lra: 11.0 };
+
+        fn params(&self) -> String {
+            return format!("I={}:TP={}:LRA={}", self.integrated, self.true_peak, self.lra);
+        }
+
+        /// Second pass filter applying the measured values with linear gain.
+        pub fn filter(&self, measured: &LoudnessMeasurement) -> String {
+            return format!(
+                "loudnorm={}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+                self.params(), measured.input_i, measured.input_tp, measured.input_lra, measured.input_thresh, measured.target_offset
+            );
+        }
+
+        /// Suffix keeping normalized copies apart from raw ones in the cache.
+        pub fn cache_tag(&self) -> String {
+            return format!(" norm{}_{}_{}", self.integrated, self.true_peak, self.lra);
+        }
+    }
+
+    /// ReplayGain 2.0 values of one track, optionally with its album's.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct ReplayGain {
+        /// Gain in dB bringing the track to `REPLAYGAIN_REFERENCE_LUFS`.
+        pub track_gain: f64,
+        /// True peak as linear amplitude, 1.0 being full scale.
+        pub track_peak: f64,
+        pub album_gain: Option<f64>,
+        pub album_peak: Option<f64>,
+    }
+
+    impl ReplayGain {
+        pub fn track(measured: &LoudnessMeasurement) -> ReplayGain {
+            return ReplayGain {
+                track_gain: REPLAYGAIN_REFERENCE_LUFS - measured.input_i,
+                track_peak: 10f64.powf(measured.input_tp / 20.0),
+                album_gain: None,
+                album_peak: None,
+            };
+        }
+
+        /// Adds album values from the album's integrated loudness and true peak.
+        pub fn with_album(mut self, integrated: f64, true_peak: f64) -> ReplayGain {
+            self.album_gain = Some(REPLAYGAIN_REFERENCE_LUFS - integrated);
+            self.album_peak = Some(10f64.powf(true_peak / 20.0));
+            return self;
+        }
+
+        /// `REPLAYGAIN_*` key/value pairs, formatted the way foobar2000 and rsgain write them.
+        pub fn tag_values(&self) -> Vec<(&'static str, String)> {
+            let mut values = vec![
+                ("REPLAYGAIN_TRACK_GAIN", format!("{:.2} dB", self.track_gain)),
+                ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", self.track_peak)),
+            ];
+            if let (Some(gain), Some(peak)) = (self.album_gain, self.album_peak) {
+                values.push(("REPLAYGAIN_ALBUM_GAIN", format!("{:.2} dB", gain)));
+                values.push(("REPLAYGAIN_ALBUM_PEAK", format!("{:.6}", peak)));
+            }
+            return values;
+        }
+
+        /// Opus `R128_*_GAIN` values: Q7.8 fixed point relative to -23 LUFS.
+        pub fn r128_values(&self) -> Vec<(&'static str, String)> {
+            let q78 = |gain: f64| -> String {
+                let gain = gain + R128_REFERENCE_LUFS - REPLAYGAIN_REFERENCE_LUFS;
+                return ((gain * 256.0).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16).to_string();
+            };
+
+            let mut values = vec![("R128_TRACK_GAIN", q78(self.track_gain))];
+            if let Some(gain) = self.album_gain {
+                values.push(("R128_ALBUM_GAIN", q78(gain)));
+            }
+            return values;
+        }
+    }
+
+    /// Integrated loudness and true peak of an album from per-track `(measurement, duration)`.
+    /// Loudness is the duration-weighted energy mean, which is close to measuring the
+    /// concatenated tracks without decoding them again.
+    pub fn album_loudness(tracks: &[(LoudnessMeasurement, f64)]) -> Option<(f64, f64)> {
+        let total: f64 = tracks.iter().map(|(_, d)| d).sum();
+        if tracks.is_empty() || total <= 0.0 {
+            return None;
+        }
+
+        let energy: f64 = tracks.iter().map(|(m, d)| d * 10f64.powf(m.input_i / 10.0)).sum::<f64>() / total;
+        let peak = tracks.iter().map(|(m, _)| m.input_tp).fold(f64::NEG_INFINITY, f64::max);
+
+        return Some((10.0 * energy.log10(), peak));
+    }
+
+    /// First pass: decode `input` and let `loudnorm` print its measurements.
+    pub fn measure_job(input: &Path, target: &LoudnessTarget) -> TranscodeJob {
+        return TranscodeJob::new(input, Path::new("-"))
+            .audio_filter(&format!("loudnorm={}:print_format=json", target.params()))
+            .output_args(["-vn", "-f", "null"]);
+    }
+
+    /// Reads the JSON block `loudnorm` prints at the end of the first pass.
+    /// ffmpeg quotes every value, so they are parsed from strings.
+    pub fn parse_measurement(log: &str) -> Option<LoudnessMeasurement> {
+        let start = log.rfind('{')?;
+        let end = start + log[start..].find('}')?;
+        let values: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&log[start..=end]).ok()?;
+
+        let get = |key: &str| -> Option<f64> {
+            return match values.get(key)? {
+                serde_json::Value::String(raw) => raw.trim().parse::<f64>().ok().filter(|v| v.is_finite()),
+                serde_json::Value::Number(n) => n.as_f64(),
+                _ => None,
+            };
+        };
+
+        return Some(LoudnessMeasurement {
+            input_i: get("input_i")?,
+            input_tp: get("input_tp")?,
+            input_lra: get("input_lra")?,
+            input_thresh: get("input_thresh")?,
+            target_offset: get("target_offset")?,
+        });
+    }
+}
+
+pub mod silence {
+    use crate::transcoder::TranscodeJob;
+    use serde::{Deserialize, Serialize};
+    use std::path::Path;
+
+    /// Silence that runs this close to the edge of the media still counts as touching it.
+    const EDGE_TOLERANCE: f64 = 0.05;
+
+    /// When ffmpeg's `silencedetect` considers audio silent.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct SilenceSettings {
+        /// Noise floor in dBFS, anything quieter is silence.
+        pub threshold_db: f64,
+        /// Shortest stretch in seconds that counts.
+        pub min_duration: f64,
+    }
+
+    /// Seconds cut from the start and end of the media.
+    #[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+    pub struct SilenceTrim {
+        pub leading: f64,
+        pub trailing: f64,
+    }
+
+    impl SilenceSettings {
+        /// Decode `input` and let `silencedetect` log every silent stretch.
+        pub fn detect_job(&self, input: &Path) -> TranscodeJob {
+            return TranscodeJob::new(input, Path::new("-"))
+                .audio_filter(&format!("silencedetect=noise={}dB:d={}", self.threshold_db, self.min_duration))
+                .output_args(["-vn", "-f", "null"]);
+        }
+
+        pub fn cache_tag(&self) -> String {
+            return format!(" trim{}_{}", self.threshold_db, self.min_duration);
+        }
+    }
+
+    impl SilenceTrim {
+        pub fn is_empty(&self) -> bool {
+            return self.leading <= 0.0 && self.trailing <= 0.0;
+        }
+
+        /// Input options keeping the audible part of `length` seconds starting at `offset`.
+        pub fn ffmpeg_input_args(&self, offset: f64, length: f64) -> Vec<String> {
+            return vec![
+                "-ss".to_string(), format!("{:.3}", offset + self.leading),
+                "-t".to_string(), format!("{:.3}", (length - self.leading - self.trailing).max(0.0)),
+            ];
+        }
+    }
+
+    /// Silent stretches `silencedetect` logged as `(start, end)`, where `end` is
+    /// missing when the silence lasts until the end of the input.
+    pub fn parse_intervals(log: &str) -> Vec<(f64, Option<f64>)> {
+        let value = |line: &str, key: &str| line.split(key).nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|x| x.parse::<f64>().ok());
+
+        let mut intervals: Vec<(f64, Option<f64>)> = Vec::new();
+        for line in log.lines().filter(|l| l.contains("silencedetect")) {
+            if let Some(start) = value(line, "silence_start:") {
+                intervals.push((start.max(0.0), None));
+            } else if let Some(end) = value(line, "silence_end:") {
+                match intervals.last_mut() {
+                    Some((_, last_end @ None)) => *last_end = Some(end),
+                    _ => intervals.push((0.0, Some(end))),
+                }
+            }
+        }
+        return intervals;
+    }
+
+    /// Silence to cut from audio of `length` seconds, given the `silencedetect` log.
+    /// Audio that is silent throughout is left alone.
+    pub fn trim_from_log(log: &str, length: f64) -> SilenceTrim {
+        let intervals = parse_intervals(log);
+
+        let leading = match intervals.first() {
+            Some((start, end)) if *start <= EDGE_TOLERANCE => end.unwrap_or(length),
+            _ => 0.0,
+        };
+        let trailing = match intervals.last() {
+            Some((start, None)) => length - start,
+            Some((start, Some(end))) if *end >= length - EDGE_TOLERANCE => length - start,
+            _ => 0.0,
+        };
+
+        if length <= 0.0 || leading + trailing >= length {
+            return SilenceTrim::default();
+        }
+        return SilenceTrim { leading: leading.max(0.0), trailing: trailing.max(0.0) };
+    }
+}
+
+pub mod waveform {
+    use crate::downloader::DownloaderError;
+    use crate::transcoder::{TranscodeJob, Transcoder};
+    use serde::{Deserialize, Serialize};
+    use std::fs;
+    use std::io::{self, BufReader, Read};
+    use std::path::{Path, PathBuf};
+
+    /// Rate audio is decoded at for peaks, plenty for drawing a few thousand columns.
+    pub const SAMPLE_RATE: u32 = 8000;
+
+    pub const DEFAULT_POINTS: usize = 1000;
+    pub const MAX_POINTS: usize = 10000;
+
+    /// Peaks in the JSON layout of BBC's audiowaveform (version 2, 8 bit, mono),
+    /// so existing players like peaks.js can read it directly.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct Waveform {
+        pub version: u8,
+        pub channels: u8,
+        pub sample_rate: u32,
+        pub samples_per_pixel: u64,
+        pub bits: u8,
+        /// Number of points, `data` holds a min and a max for each.
+        pub length: usize,
+        pub data: Vec<i8>,
+    }
+
+    /// Decodes `input` to mono 16 bit PCM at `SAMPLE_RATE`.
+    pub fn decode_job(input: &Path, output: &Path) -> TranscodeJob {
+        return TranscodeJob::new(input, output)
+            .output_args(["-vn", "-ac", "1", "-ar", &SAMPLE_RATE.to_string(), "-f", "s16le"]);
+    }
+
+    /// Min/max peaks of `samples` little endian i16 samples read from `pcm`, in at most `points` buckets.
+    pub fn peaks<R: Read>(pcm: R, samples: u64, points: usize) -> Result<Waveform, io::Error> {
+        let samples_per_pixel = samples.div_ceil(points.max(1) as u64).max(1);
+        let mut data: Vec<i8> = Vec::with_capacity(points * 2);
+
+        let mut reader = BufReader::new(pcm);
+        let mut sample = [0u8; 2];
+        let (mut min, mut max, mut count) = (i16::MAX, i16::MIN, 0u64);
+        loop {
+            match reader.read_exact(&mut sample) {
+                Ok(()) => {},
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            let value = i16::from_le_bytes(sample);
+            min = min.min(value);
+            max = max.max(value);
+            count += 1;
+
+            if count == samples_per_pixel {
+                data.extend([(min >> 8) as i8, (max >> 8) as i8]);
+                (min, max, count) = (i16::MAX, i16::MIN, 0);
+            }
+        }
+        if count > 0 {
+            data.extend([(min >> 8) as i8, (max >> 8) as i8]);
+        }
+
+        return Ok(Waveform {
+            version: 2,
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            samples_per_pixel,
+            bits: 8,
+            length: data.len() / 2,
+            data,
+        });
+    }
+
+    /// `<file>.peaks<points>.json` next to the media.
+    pub fn cache_path(media: &Path, points: usize) -> PathBuf {
+        let mut name = media.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".peaks{}.json", points));
+        return media.with_file_name(name);
+    }
+
+    /// Peaks of `media`, read from its cache file or computed and cached.
+    pub async fn waveform(transcoder: &dyn Transcoder, media: &Path, points: usize) -> Result<Waveform, DownloaderError> {
+        let cached = cache_path(media, points);
+        if let Some(waveform) = fs::read(&cached).ok().and_then(|raw| serde_json::from_slice(&raw).ok()) {
+            return Ok(waveform);
+        }
+
+        // Unique per request, concurrent requests for the same file must not share it.
+        let pcm = media.with_file_name(format!(".{}.pcm", uuid::Uuid::new_v4().simple()));
+
+        let res = match transcoder.run(&decode_job(media, &pcm)).await {
+            Ok(_) => {
+                let path = pcm.clone();
+                tokio::task::spawn_blocking(move || -> Result<Waveform, io::Error> {
+                    let samples = fs::metadata(&path)?.len() / 2;
+                    peaks(fs::File::open(&path)?, samples, points)
+                }).await
+                    .map_err(|e| DownloaderError::Io(io::Error::other(e)))
+                    .and_then(|r| r.map_err(DownloaderError::from))
+            },
+            Err(e) => Err(e),
+        };
+        let _ = fs::remove_file(&pcm);
+        let waveform = res?;
+
+        let raw = serde_json::to_vec(&waveform).map_err(io::Error::other)?;
+        let partial = pcm.with_extension("json");
+        fs::write(&partial, raw)?;
+        fs::rename(&partial, &cached)?;
+
+        return Ok(waveform);
+    }
+}
+
+pub mod streaming {
+    use crate::downloader::{get_audio_source, DownloaderError, VideoId};
+    use actix_web::web::Bytes;
+    use futures_util::{Stream, StreamExt};
+    use std::collections::HashMap;
+    use std::io;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    /// URLs this close to their `expire` are refreshed before use.
+    const EXPIRY_MARGIN_SECS: u64 = 60;
+
+    /// Resolved URLs are dropped after this long, also those without an `expire`.
+    const SOURCE_TTL: Duration = Duration::from_secs(60 * 60);
+
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Longest upstream may stay silent, for the response headers and between body chunks.
+    const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Upstream stream URL resolved by yt-dlp.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct StreamSource {
+        pub url: String,
+        pub content_type: String,
+        /// Headers yt-dlp says the URL has to be requested with.
+        pub headers: Vec<(String, String)>,
+    }
+
+    /// Remembers resolved stream URLs so the range requests of one playback
+    /// don't each run yt-dlp.
+    pub struct StreamCache {
+        sources: Mutex<HashMap<String, (StreamSource, Instant)>>,
+        client: reqwest::Client,
+    }
+
+    /// Audio content type from yt-dlp's `ext` and `acodec`; the upstream's own is
+    /// often `application/octet-stream`.
+    pub fn content_type(ext: Option<&str>, acodec: Option<&str>) -> &'static str {
+        return match (ext, acodec) {
+            (Some("webm"), _) => "audio/webm",
+            (Some("m4a" | "mp4"), _) => "audio/mp4",
+            (Some("mp3"), _) | (_, Some("mp3")) => "audio/mpeg",
+            (_, Some(codec)) if codec.starts_with("opus") => "audio/ogg; codecs=opus",
+            _ => "application/octet-stream",
+        }
+    }
+
+    /// Whether the `expire` timestamp googlevideo puts in its URLs is (nearly) reached.
+    /// URLs without one are trusted until upstream rejects them.
+    pub fn is_expired(url: &str, now: u64) -> bool {
+        let expire = url::Url::parse(url).ok()
+            .and_then(|u| u.query_pairs().find(|(k, _)| k == "expire").and_then(|(_, v)| v.parse::<u64>().ok()));
+
+        return expire.is_some_and(|expire| expire <= now + EXPIRY_MARGIN_SECS);
+    }
+
+    /// Upstream answers with these once a URL expired or is bound to another IP.
+    pub fn is_stale_status(status: reqwest::StatusCode) -> bool {
+        return matches!(status.as_u16(), 403 | 404 | 410);
+    }
+
+    /// Body of `resp`, failing once upstream stalls for `READ_TIMEOUT`.
+    /// A broken body is not resumed here, the response simply ends early and players
+    /// request the rest with a `Range`, which `StreamCache::open` serves from a fresh URL if needed.
+    pub fn body(resp: reqwest::Response) -> impl Stream<Item = Result<Bytes, io::Error>> {
+        futures_util::stream::unfold(Some(Box::pin(resp.bytes_stream())), |stream| async move {
+            let mut stream = stream?;
+            match tokio::time::timeout(READ_TIMEOUT, stream.next()).await {
+                Ok(Some(Ok(chunk))) => Some((Ok(chunk), Some(stream))),
+                Ok(Some(Err(e))) => Some((Err(io::Error::other(e)), None)),
+                Ok(None) => None,
+                Err(_) => Some((Err(io::Error::new(io::ErrorKind::TimedOut, "upstream stream stalled")), None)),
+            }
+        })
+    }
+
+    impl StreamCache {
+        pub fn new() -> StreamCache {
+            return StreamCache {
+                sources: Mutex::new(HashMap::new()),
+                client: reqwest::Client::builder()
+                    .connect_timeout(CONNECT_TIMEOUT)
+                    .build()
+                    .expect("Could not build HTTP client."),
+            };
+        }
+
+        pub fn insert(&self, id: &VideoId, source: StreamSource) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            let mut sources = self.sources.lock().unwrap();
+
+            sources.retain(|_, (s, added)| !is_expired(&s.url, now) && added.elapsed() < SOURCE_TTL);
+            sources.insert(id.as_str().to_string(), (source, Instant::now()));
+        }
+
+        async fn source(&self, id: &VideoId, refresh: bool) -> Result<StreamSource, DownloaderError> {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            let cached = self.sources.lock().unwrap().get(id.as_str()).cloned();
+
+            if let Some((source, _)) = cached.filter(|(s, added)| !refresh && !is_expired(&s.url, now) && added.elapsed() < SOURCE_TTL) {
+                return Ok(source);
+            }
+
+            let source = get_audio_source(id).await?;
+            self.insert(id, source.clone());
+
+            return Ok(source);
+        }
+
+        /// Requests the audio of `id` from upstream, forwarding the client's `Range`.
+        /// An expired or rejected URL is resolved again once, so playback continues.
+        pub async fn open(&self, id: &VideoId, range: Option<&str>) -> Result<(reqwest::Response, String), DownloaderError> {
+            let mut refresh = false;
+            loop {
+                let source = self.source(id, refresh).await?;
+
+                let mut request = self.client.get(&source.url);
+                for (name, value) in &source.headers {
+                    request = request.header(name.as_str(), value.as_str());
+                }
+                if let Some(range) = range {
+                    request = request.header(reqwest::header::RANGE, range);
+                }
+
+                let resp = tokio::time::timeout(READ_TIMEOUT, request.send()).await
+                    .map_err(|_| DownloaderError::Upstream("Stream request timed out".to_string()))?
+                    .map_err(|e| DownloaderError::Upstream(format!("Stream request failed: {}", e)))?;
+
+                if is_stale_status(resp.status()) && !refresh {
+                    println!("Stream URL of {} was rejected with {}, resolving it again", id, resp.status());
+                    refresh = true;
+                    continue;
+                }
+                if !resp.status().is_success() && resp.status() != reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+                    return Err(DownloaderError::Upstream(format!("Stream of {} answered {}", id, resp.status())));
+                }
+
+                return Ok((resp, source.content_type));
+            }
+        }
+    }
+
+    impl Default for StreamCache {
+        fn default() -> StreamCache {
+            return StreamCache::new();
+        }
+    }
+}
+
+pub mod transcoder {
+    use crate::downloader::DownloaderError;
+    use crate::progress;
+    use std::env;
+    use std::future::Future;
+    use std::path::{Path, PathBuf};
+    use std::pin::Pin;
+    use std::process::{Command, Stdio};
+    use std::sync::OnceLock;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    /// Oldest ffmpeg major version with the filters and encoders we rely on.
+    pub const MIN_FFMPEG_MAJOR: u32 = 4;
+
+    /// One transcoder invocation: what to read, how to filter it and where to write it.
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct TranscodeJob {
+        pub inputs: Vec<PathBuf>,
+        /// Options placed before the first input, e.g. seeking.
+        pub input_args: Vec<String>,
+        pub audio_filters: Vec<String>,
+        pub video_filters: Vec<String>,
+        /// Codec and muxer options for the output.
+        pub output_args: Vec<String>,
+        pub output: PathBuf,
+    }
+
+    impl TranscodeJob {
+        pub fn new(input: &Path, output: &Path) -> TranscodeJob {
+            return TranscodeJob {
+                inputs: vec![input.to_path_buf()],
+                output: output.to_path_buf(),
+                ..TranscodeJob::default()
+            };
+        }
+
+        /// Adds another input, e.g. a subtitle file to mux in.
+        pub fn input(mut self, input: &Path) -> TranscodeJob {
+            self.inputs.push(input.to_path_buf());
+            return self;
+        }
+
+        pub fn input_args<I, S>(mut self, args: I) -> TranscodeJob
+        where
+            I: IntoIterator<Item = S>,
+            S: Into<String>,
+        {
+            self.input_args.extend(args.into_iter().map(|x| x.into()));
+            return self;
+        }
+
+        pub fn output_args<I, S>(mut self, args: I) -> TranscodeJob
+        where
+            I: IntoIterator<Item = S>,
+            S: Into<String>,
+        {
+            self.output_args.extend(args.into_iter().map(|x| x.into()));
+            return self;
+        }
+
+        pub fn audio_filter(mut self, filter: &str) -> TranscodeJob {
+            self.audio_filters.push(filter.to_string());
+            return self;
+        }
+
+        pub fn video_filter(mut self, filter: &str) -> TranscodeJob {
+            self.video_filters.push(filter.to_string());
+            return self;
+        }
+
+        /// ffmpeg command line for this job.
+        pub fn ffmpeg_args(&self) -> Vec<String> {
+            let mut args: Vec<String> = vec!["-y".to_string(), "-hide_banner".to_string()];
+            args.extend(self.input_args.iter().cloned());
+            for input in &self.inputs {
+                args.extend(["-i".to_string(), input.to_string_lossy().to_string()]);
+            }
+            if !self.video_filters.is_empty() {
+                args.extend(["-vf".to_string(), self.video_filters.join(",")]);
+            }
+            if !self.audio_filters.is_empty() {
+                args.extend(["-af".to_string(), self.audio_filters.join(",")]);
+            }
+            args.extend(self.output_args.iter().cloned());
+            args.push(self.output.to_string_lossy().to_string());
+
+            return args;
+        }
+    }
+
+    pub type TranscodeFuture<'a> = Pin<Box<dyn Future<Output = Result<String, DownloaderError>> + Send + 'a>>;
+
+    pub trait Transcoder: Send + Sync {
+        /// Runs `job` to completion and returns the transcoder's diagnostic log.
+        /// Must not block: it is awaited on the server's worker threads.
+        fn run<'a>(&'a self, job: &'a TranscodeJob) -> TranscodeFuture<'a>;
+    }
+
+    /// Transcoder backed by an ffmpeg binary.
+    #[derive(Debug, Clone)]
+    pub struct Ffmpeg {
+        pub path: PathBuf,
+        pub version: String,
+    }
+
+    impl Ffmpeg {
+        /// Finds ffmpeg in the configured path, then on `$PATH`, then next to the executable's working dir,
+        /// and checks that it is recent enough.
+        pub fn discover(configured: Option<&Path>) -> Result<Ffmpeg, DownloaderError> {
+            let path = find_ffmpeg(configured)
+                .ok_or_else(|| DownloaderError::Transcode("ffmpeg not found, set FFMPEG_PATH or install it on PATH".to_string()))?;
+
+            let output = Command::new(&path).arg("-version").output()
+                .map_err(|e| DownloaderError::Transcode(format!("could not start {}: {}", path.display(), e)))?;
+            let banner = String::from_utf8_lossy(&output.stdout);
+            let version = parse_version(&banner)
+                .ok_or_else(|| DownloaderError::Transcode(format!("{} did not report a version", path.display())))?;
+
+            match version.split('.').next().and_then(|m| m.parse::<u32>().ok()) {
+                Some(major) if major < MIN_FFMPEG_MAJOR => {
+                    return Err(DownloaderError::Transcode(format!("ffmpeg {} is too old, need {} or newer", version, MIN_FFMPEG_MAJOR)));
+                },
+                // Git builds report e.g. "N-112345-g..." and are assumed current.
+                _ => {},
+            }
+
+            return Ok(Ffmpeg { path, version });
+        }
+    }
+
+    impl Transcoder for Ffmpeg {
+        /// Turns ffmpeg's `Duration:` / `time=` stats on stderr into progress events.
+        fn run<'a>(&'a self, job: &'a TranscodeJob) -> TranscodeFuture<'a> {
+            Box::pin(async move {
+                let mut cmd = tokio::process::Command::new(&self.path)
+                    .args(job.ffmpeg_args())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .map_err(|e| DownloaderError::Transcode(format!("could not start ffmpeg: {}", e)))?;
+
+                let mut log = String::new();
+                let mut total: Option<f64> = None;
+
+                // ffmpeg rewrites its stats line with '\r', so split on both line endings.
+                let mut chunks = BufReader::new(cmd.stderr.take().unwrap()).split(b'\r');
+                while let Ok(Some(chunk)) = chunks.next_segment().await {
+                    for line in String::from_utf8_lossy(&chunk).lines() {
+                        if total.is_none() {
+                            total = progress::parse_ffmpeg_duration(line);
+                        }
+                        match progress::parse_ffmpeg_progress(line, total) {
+                            Some(event) => crate::jobs::report_progress(event),
+                            None => {
+                                log.push_str(line);
+                                log.push('\n');
+                            },
+                        }
+                    }
+                }
+                let status = cmd.wait().await?;
+                if !status.success() {
+                    let tail: Vec<&str> = log.lines().rev().take(5).collect();
+                    return Err(DownloaderError::Transcode(format!("ffmpeg exited with {}: {}", status, tail.into_iter().rev().collect::<Vec<_>>().join(" | "))));
+                }
+
+                return Ok(log);
+            })
+        }
+    }
+
+    static FFMPEG: OnceLock<Result<Ffmpeg, String>> = OnceLock::new();
+
+    /// The process-wide ffmpeg, discovered from `FFMPEG_PATH` on first use.
+    pub fn ffmpeg() -> Result<&'static Ffmpeg, DownloaderError> {
+        return FFMPEG.get_or_init(|| {
+            let c : crate::Configuration = envy::from_env::<crate::Configuration>().expect("Provide config.");
+            Ffmpeg::discover(c.ffmpeg_path.as_deref().map(Path::new)).map_err(|e| e.to_string())
+        })
+        .as_ref()
+        .map_err(|e| DownloaderError::Transcode(e.clone()));
+    }
+
+    fn find_ffmpeg(configured: Option<&Path>) -> Option<PathBuf> {
+        if let Some(path) = configured {
+            return path.is_file().then(|| path.to_path_buf());
+        }
+
+        let names: &[&str] = if cfg!(windows) { &["ffmpeg.exe"] } else { &["ffmpeg", "ffmpeg.exe"] };
+
+        if let Some(paths) = env::var_os("PATH") {
+            for dir in env::split_paths(&paths) {
+                if let Some(found) = names.iter().map(|n| dir.join(n)).find(|p| p.is_file()) {
+                    return Some(found);
+                }
+            }
+        }
+
+        let root = env::current_dir().ok()?;
+        return names.iter().map(|n| root.join(n)).find(|p| p.is_file());
+    }
+
+    /// Version from the first line of `ffmpeg -version`, e.g. "6.1.1" or "N-112345-gabcdef".
+    pub fn parse_version(banner: &str) -> Option<String> {
+        let rest = banner.lines().next()?.strip_prefix("ffmpeg version ")?;
+        let version = rest.split_whitespace().next()?;
+        let version = version.trim_start_matches('n');
+
+        return Some(version.split('-').next().filter(|v| v.chars().next().is_some_and(|c| c.is_ascii_digit())).unwrap_or(version).to_string());
+    }
+}
+
+pub mod progress {
+    use crate::jobs::JobState;
+    use serde::Serialize;
+
+    /// One structured progress update of a running job.
+    #[derive(Serialize, Debug, Clone, PartialEq)]
+    pub struct ProgressEvent {
+        pub state: JobState,
+        pub percent: Option<f64>,
+        pub speed: Option<String>,
+        /// Seconds left, as estimated by yt-dlp.
+        pub eta: Option<u64>,
+        /// Seconds of media ffmpeg has written so far.
+        pub time: Option<f64>,
+        pub error: Option<String>,
+    }
+
+    impl ProgressEvent {
+        pub fn new(state: JobState) -> ProgressEvent {
+            return ProgressEvent { state, percent: None, speed: None, eta: None, time: None, error: None };
+        }
+    }
+
+    /// Parses a yt-dlp `--newline` progress line like
+    /// `[download]  45.3% of ~  3.45MiB at    1.23MiB/s ETA 00:02 (frag 3/10)`.
+    pub fn parse_ytdlp_progress(line: &str) -> Option<ProgressEvent> {
+        let rest = line.trim().strip_prefix("[download]")?;
+        let tokens: Vec<&str> = rest.split_whitespace().collect();
+
+        let percent = tokens.iter()
+            .find_map(|t| t.strip_suffix('%'))
+            .and_then(|p| p.parse::<f64>().ok())?;
+
+        let after = |key: &str| tokens.iter()
+            .position(|t| *t == key)
+            .and_then(|i| tokens.get(i + 1))
+            .map(|x| x.to_string());
+
+        let mut event = ProgressEvent::new(JobState::Downloading);
+        event.percent = Some(percent);
+        event.speed = after("at").filter(|s| !s.starts_with("Unknown"));
+        event.eta = after("ETA").and_then(|e| crate::downloader::parse_timestamp(&e));
+
+        return Some(event);
+    }
+
+    /// Parses the `Duration: 00:03:25.12, start: ...` header ffmpeg prints for its input.
+    pub fn parse_ffmpeg_duration(line: &str) -> Option<f64> {
+        let rest = line.trim().strip_prefix("Duration:")?;
+        let value = rest.split(',').next()?.trim();
+
+        return parse_clock(value);
+    }
+
+    /// Parses an ffmpeg stats line (`size=... time=00:01:02.50 bitrate=... speed=1.5x`).
+    pub fn parse_ffmpeg_progress(line: &str, total: Option<f64>) -> Option<ProgressEvent> {
+        let value = |key: &str| line.split_whitespace()
+            .find_map(|t| t.strip_prefix(key))
+            .map(|x| x.to_string());
+
+        let time = value("time=").and_then(|t| parse_clock(&t))?;
+
+        let mut event = ProgressEvent::new(JobState::Transcoding);
+        event.time = Some(time);
+        event.speed = value("speed=").filter(|s| s != "N/A");
+        event.percent = total
+            .filter(|t| *t > 0.0)
+            .map(|t| (time / t * 100.0).clamp(0.0, 100.0));
+
+        return Some(event);
+    }
+
+    /// Parses `HH:MM:SS.xx` into seconds.
+    fn parse_clock(raw: &str) -> Option<f64> {
+        let mut total = 0.0;
+        let parts: Vec<&str> = raw.split(':').collect();
+
+        if parts.len() != 3 {
+            return None;
+        }
+        for part in parts {
+            total = total * 60.0 + part.parse::<f64>().ok()?;
+        }
+
+        return Some(total);
+    }
+}
+
+pub mod jobs {
+    use crate::downloader::DownloaderError;
+    use crate::progress::ProgressEvent;
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+    use serde::Serialize;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use tokio::sync::{broadcast, Semaphore};
+
+    /// Finished and failed jobs are forgotten after this long.
+    const JOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+    #[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum JobState {
+        Queued,
+        Downloading,
+        Transcoding,
+        Done,
+        Failed,
+    }
+
+    #[derive(Serialize, Debug, Clone)]
+    pub struct JobStatus {
+        pub id: String,
+        pub state: JobState,
+        pub error: Option<String>,
+        /// HTTP status of the error the job failed with.
+        #[serde(skip)]
+        pub error_status: Option<StatusCode>,
+        pub file_name: Option<String>,
+        pub progress: Option<ProgressEvent>,
+        /// Facts about the finished file, e.g. the silence that was cut.
+        #[serde(skip_serializing_if = "serde_json::Map::is_empty")]
+        pub result: serde_json::Map<String, serde_json::Value>,
+        pub created_at: u64,
+        #[serde(skip)]
+        pub path: Option<PathBuf>,
+        #[serde(skip)]
+        pub events: broadcast::Sender<ProgressEvent>,
+        #[serde(skip)]
+        pub content_type: String,
+        #[serde(skip)]
+        updated: Instant,
+    }
+
+    /// In-memory registry of background download jobs.
+    pub struct JobStore {
+        jobs: Mutex<HashMap<String, JobStatus>>,
+        permits: Semaphore,
+    }
+
+    tokio::task_local! {
+        static CURRENT_JOB: (Arc<JobStore>, String);
+    }
+
+    /// Updates the state of the job the calling task runs in. Outside of a job
+    /// (plain HTTP downloads) this is a no-op.
+    pub fn report(state: JobState) {
+        let _ = CURRENT_JOB.try_with(|(store, id)| store.set_state(id, state));
+    }
+
+    /// Publishes a progress update for the job the calling task runs in.
+    pub fn report_progress(event: ProgressEvent) {
+        let _ = CURRENT_JOB.try_with(|(store, id)| store.publish(id, event));
+    }
+
+    /// Records `value` under `key` in the result of the job the calling task runs in.
+    pub fn report_result<T: Serialize>(key: &str, value: &T) {
+        let _ = CURRENT_JOB.try_with(|(store, id)| store.set_result(id, key, value));
+    }
+
+    impl JobStore {
+        pub fn new(max_concurrent: usize) -> JobStore {
+            return JobStore {
+                jobs: Mutex::new(HashMap::new()),
+                permits: Semaphore::new(max_concurrent.max(1)),
+            };
+        }
+
+        pub fn get(&self, id: &str) -> Option<JobStatus> {
+            return self.jobs.lock().unwrap().get(id).cloned();
+        }
+
+        pub fn set_state(&self, id: &str, state: JobState) {
+            self.publish(id, ProgressEvent::new(state));
+        }
+
+        pub fn set_result<T: Serialize>(&self, id: &str, key: &str, value: &T) {
+            if let (Some(job), Ok(value)) = (self.jobs.lock().unwrap().get_mut(id), serde_json::to_value(value)) {
+                job.result.insert(key.to_string(), value);
+            }
+        }
+
+        pub fn publish(&self, id: &str, event: ProgressEvent) {
+            if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+                job.state = event.state;
+                job.updated = Instant::now();
+                let _ = job.events.send(event.clone());
+                job.progress = Some(event);
+            }
+        }
+
+        /// Current status plus a receiver for every update after it.
+        pub fn subscribe(&self, id: &str) -> Option<(JobStatus, broadcast::Receiver<ProgressEvent>)> {
+            let jobs = self.jobs.lock().unwrap();
+            let job = jobs.get(id)?;
+
+            return Some((job.clone(), job.events.subscribe()));
+        }
+
+        fn create(&self, content_type: &str) -> String {
+            let id = uuid::Uuid::new_v4().simple().to_string();
+            let created_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.retain(|_, j| {
+                !matches!(j.state, JobState::Done | JobState::Failed) || j.updated.elapsed() < JOB_TTL
+            });
+            jobs.insert(id.clone(), JobStatus {
+                id: id.clone(),
+                state: JobState::Queued,
+                error: None,
+                error_status: None,
+                file_name: None,
+                progress: None,
+                result: serde_json::Map::new(),
+                created_at,
+                path: None,
+                events: broadcast::channel(64).0,
+                content_type: content_type.to_string(),
+                updated: Instant::now(),
+            });
+
+            return id;
+        }
+
+        fn finish(&self, id: &str, res: Result<PathBuf, DownloaderError>) {
+            let event = match &res {
+                Ok(_) => {
+                    let mut event = ProgressEvent::new(JobState::Done);
+                    event.percent = Some(100.0);
+                    event
+                },
+                Err(e) => {
+                    let mut event = ProgressEvent::new(JobState::Failed);
+                    event.error = Some(e.to_string());
+                    event
+                }
+            };
+
+            if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+                match res {
+                    Ok(path) => {
+                        job.file_name = path.file_name().map(|x| x.to_string_lossy().to_string());
+                        job.path = Some(path);
+                    },
+                    Err(e) => {
+                        job.error = Some(e.to_string());
+                        job.error_status = Some(e.status_code());
+                    }
+                }
+            }
+            self.publish(id, event);
+        }
+
+        /// Registers a job and runs `work` in the background once a slot is free.
+        pub fn spawn<F>(self: Arc<Self>, content_type: &str, work: F) -> String
+        where
+            F: Future<Output = Result<PathBuf, DownloaderError>> + 'static,
+        {
+            let id = self.create(content_type);
+            let store = self.clone();
+            let job_id = id.clone();
+
+            actix_web::rt::spawn(async move {
+                let _permit = store.permits.acquire().await;
+                store.set_state(&job_id, JobState::Downloading);
+
+                let res = CURRENT_JOB.scope((store.clone(), job_id.clone()), work).await;
+                if let Err(e) = &res {
+                    println!("Job {} failed: {}", job_id, e);
+                }
+                store.finish(&job_id, res);
+            });
+
+            return id;
+        }
+    }
+}
+#[cfg(test)]
+mod test {
+    use std::env;
+    use super::*;
+    use actix_web::test::TestRequest;
+    use tokio::runtime::Runtime;
+
+
+    #[test]
+    fn test_extract_id_passing() {
+        let link = "https://www.youtube.com/watch?v=JIvKgSyvtxI&fbclid=abcdssf".to_owned();
+
+        let id = parse_link(&link).unwrap().id;
+        assert_eq!(id, "JIvKgSyvtxI");
+    }
+    #[test]
+    fn test_extract_id_passing2() {
+        let link = "https://www.youtube.com/watch?v=PpjdTwQwWWY".to_owned();
+
+        let id = parse_link(&link).unwrap().id;
+        assert_eq!(id, "PpjdTwQwWWY");
+    }
+
+    #[test]
+    fn test_extract_id_failing() {
+        let link = "https://www.youtube.com/watch?v=gibb".to_owned();
+
+        let id = parse_link(&link);
+
+        assert!(id.is_none());
+    }
+    #[test]
+    fn test_extract_id_failing2() {
+        let link = "https://www.youtube.com/watch?v=gibberishtoofuckinglong".to_owned();
+
+        let id = parse_link(&link);
+
+        assert!(id.is_none());
+    }
+
+    #[test]
+    fn test_extract_id_url_shapes() {
+        let links = [
+            "https://youtu.be/PpjdTwQwWWY",
+            "https://www.youtube.com/shorts/PpjdTwQwWWY",
+            "https://www.youtube.com/embed/PpjdTwQwWWY?rel=0",
+            "https://www.youtube.com/live/PpjdTwQwWWY?feature=share",
+            "https://music.youtube.com/watch?v=PpjdTwQwWWY",
+            "https://m.youtube.com/watch?v=PpjdTwQwWWY",
+            "https://www.youtube.com/watch?feature=x&v=PpjdTwQwWWY",
+            "youtube.com/watch?v=PpjdTwQwWWY",
+            "PpjdTwQwWWY",
+        ];
+
+        for link in links {
+            let id = parse_link(link).map(|l| l.id);
+            assert_eq!(id.unwrap(), "PpjdTwQwWWY", "{}", link);
+        }
+    }
+
+    #[test]
+    fn test_extract_id_failing_chars() {
+        assert!(parse_link("https://www.youtube.com/watch?v=PpjdTwQ$WWY").is_none());
+        assert!(parse_link("PpjdTwQ WWY").is_none());
+        assert!(parse_link("https://example.com/watch?v=PpjdTwQwWWY").is_none());
+    }
+
+    #[test]
+    fn test_parse_link_context() {
+        let link = parse_link("https://youtu.be/PpjdTwQwWWY?t=1m30s&list=PLx0sYbCqOb8TBPRdmBHs5Iftvv9TPboYG").unwrap();
+
+        assert_eq!(link.id, "PpjdTwQwWWY");
+        assert_eq!(link.start, Some(90));
+        assert_eq!(link.list.unwrap(), "PLx0sYbCqOb8TBPRdmBHs5Iftvv9TPboYG");
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("90"), Some(90));
+        assert_eq!(parse_timestamp("90s"), Some(90));
+        assert_eq!(parse_timestamp("1h2m3s"), Some(3723));
+        assert_eq!(parse_timestamp("01:02:03"), Some(3723));
+        assert_eq!(parse_timestamp("1x"), None);
+        assert_eq!(parse_timestamp("12m3"), None);
+        assert_eq!(parse_timestamp("99999999999999999999:00"), None);
+        assert_eq!(parse_timestamp("999999999999999:00:00:00"), None);
+        assert_eq!(parse_timestamp("9999999999999999999h"), None);
+    }
+
+    #[test]
+    fn test_write_zip_order() {
+        let dir = env::temp_dir().join("r_webaudioprov_test_zip");
+        fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("a.mp3");
+        let b = dir.join("b.mp3");
+        fs::write(&a, b"first").unwrap();
+        fs::write(&b, b"second").unwrap();
+
+        let out = dir.join("out.zip");
+        write_zip(&[("01 - b.mp3".to_string(), b), ("02 - a.mp3".to_string(), a)], &out).unwrap();
+
+        let mut archive = zip::ZipArchive::new(fs::File::open(&out).unwrap()).unwrap();
+        assert_eq!(archive.len(), 2);
+        assert_eq!(archive.by_index(0).unwrap().name(), "01 - b.mp3");
+        assert_eq!(archive.by_index(1).unwrap().name(), "02 - a.mp3");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_playlist_size() {
+        let entries = vec![
+            youtube_dl::SingleVideo { filesize: Some(1000), ..Default::default() },
+            youtube_dl::SingleVideo { filesize_approx: Some(500.5), ..Default::default() },
+            youtube_dl::SingleVideo::default(),
+        ];
+
+        assert_eq!(playlist_size(&entries), 1500);
+        assert_eq!(playlist_size(&[]), 0);
+    }
+
+    #[test]
+    fn test_is_allowed_url() {
+        let hosts = vec!["soundcloud.com".to_string(), "bandcamp.com".to_string()];
+
+        assert!(is_allowed_url("https://soundcloud.com/artist/track", &hosts));
+        assert!(is_allowed_url("https://artist.bandcamp.com/track/song", &hosts));
+        assert!(!is_allowed_url("https://evilsoundcloud.com/track", &hosts));
+        assert!(!is_allowed_url("file:///etc/passwd", &hosts));
+        assert!(!is_allowed_url("not a url", &hosts));
+    }
+
+    #[test]
+    fn test_media_key() {
+        let mut video = youtube_dl::SingleVideo { id: "PpjdTwQwWWY".to_string(), extractor_key: Some("Youtube".to_string()), ..Default::default() };
+        assert_eq!(media_key(&video), "PpjdTwQwWWY");
+
+        video.id = "12345/678".to_string();
+        video.extractor_key = Some("Soundcloud".to_string());
+        assert_eq!(media_key(&video), "soundcloud-12345_678");
+    }
+
+    #[test]
+    fn test_error_status_codes() {
+        assert_eq!(DownloaderError::InvalidId("x".to_string()).status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(DownloaderError::NotFound("x".to_string()).status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(DownloaderError::DurationExceeded("x".to_string()).status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(DownloaderError::Upstream("x".to_string()).status_code(), StatusCode::BAD_GATEWAY);
+        assert_eq!(DownloaderError::Transcode("x".to_string()).status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_error_response_content_type() {
+        let req = TestRequest::default().uri("/download_id/gibb").to_http_request();
+        let resp = error_response(&req, DownloaderError::InvalidId("Invalid video id".to_string()));
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/problem+json");
+
+        let req = TestRequest::default().insert_header(("HX-Request", "true")).to_http_request();
+        let resp = error_response(&req, DownloaderError::InvalidId("Invalid video id".to_string()));
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/html; charset=utf-8");
+    }
+
+    #[test]
+    fn test_job_lifecycle() {
+        use std::sync::Arc;
+
+        actix_web::rt::System::new().block_on(async {
+            let store = Arc::new(JobStore::new(1));
+
+            let done = store.clone().spawn("audio/mpeg", async {
+                jobs::report(JobState::Transcoding);
+                Ok(PathBuf::from("temp/song [PpjdTwQwWWY].mp3"))
+            });
+            let failed = store.clone().spawn("audio/mpeg", async {
+                Err(DownloaderError::NotFound("gone".to_string()))
+            });
+
+            assert_eq!(store.get(&done).unwrap().state, JobState::Queued);
+
+            let job = wait_for_job(&store, &done).await;
+            assert_eq!(job.state, JobState::Done);
+            assert_eq!(job.file_name.as_deref(), Some("song [PpjdTwQwWWY].mp3"));
+
+            let job = wait_for_job(&store, &failed).await;
+            assert_eq!(job.state, JobState::Failed);
+            assert_eq!(job.error.as_deref(), Some("gone"));
+
+            let app = actix_web::test::init_service(App::new().app_data(web::Data::from(store.clone())).service(get_job_file)).await;
+            let req = TestRequest::get().uri(&format!("/jobs/{}/file", failed)).to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::CONFLICT);
+            assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "application/problem+json");
+        });
+    }
+
+    /// Waits until the job is done or failed, without guessing how long it takes.
+    async fn wait_for_job(store: &JobStore, id: &str) -> jobs::JobStatus {
+        let terminal = |state: JobState| matches!(state, JobState::Done | JobState::Failed);
+        let (job, mut rx) = store.subscribe(id).unwrap();
+
+        if !terminal(job.state) {
+            tokio::time::timeout(std::time::Duration::from_secs(5), async {
+                loop {
+                    match rx.recv().await {
+                        Ok(event) if terminal(event.state) => break,
+                        Err(RecvError::Closed) => break,
+                        _ => {},
+                    }
+                }
+            }).await.expect("job did not finish in time");
+        }
+
+        store.get(id).unwrap()
+    }
+
+    #[test]
+    fn test_parse_ytdlp_progress() {
+        let event = progress::parse_ytdlp_progress("[download]  45.3% of ~  3.45MiB at    1.23MiB/s ETA 00:02 (frag 3/10)").unwrap();
+
+        assert_eq!(event.state, JobState::Downloading);
+        assert_eq!(event.percent, Some(45.3));
+        assert_eq!(event.speed.as_deref(), Some("1.23MiB/s"));
+        assert_eq!(event.eta, Some(2));
+
+        let event = progress::parse_ytdlp_progress("[download] 100% of    3.45MiB in 00:00:03 at 1.10MiB/s").unwrap();
+        assert_eq!(event.percent, Some(100.0));
+
+        assert!(progress::parse_ytdlp_progress("[download] Destination: [PpjdTwQwWWY].webm").is_none());
+        assert!(progress::parse_ytdlp_progress("[youtube] PpjdTwQwWWY: Downloading webpage").is_none());
+    }
+
+    #[test]
+    fn test_parse_ffmpeg_progress() {
+        let total = progress::parse_ffmpeg_duration("  Duration: 00:03:20.00, start: 0.007000, bitrate: 130 kb/s");
+        assert_eq!(total, Some(200.0));
+
+        let line = "size=    2048kB time=00:01:40.00 bitrate= 167.8kbits/s speed=41.2x";
+        let event = progress::parse_ffmpeg_progress(line, total).unwrap();
+
+        assert_eq!(event.state, JobState::Transcoding);
+        assert_eq!(event.time, Some(100.0));
+        assert_eq!(event.percent, Some(50.0));
+        assert_eq!(event.speed.as_deref(), Some("41.2x"));
+
+        assert!(progress::parse_ffmpeg_progress("Stream #0:0: Audio: opus, 48000 Hz", total).is_none());
+    }
+
+    #[test]
+    fn test_media_format_parse() {
+        assert_eq!(MediaFormat::parse("mp4"), Some(MediaFormat::Video(VideoContainer::Mp4)));
+        assert_eq!(MediaFormat::parse("webm"), Some(MediaFormat::Video(VideoContainer::Webm)));
+        assert_eq!(MediaFormat::parse("mkv").unwrap().content_type(), "video/x-matroska");
+        assert_eq!(MediaFormat::parse("FLAC"), Some(MediaFormat::Audio(AudioFormat::Flac)));
+        assert_eq!(MediaFormat::parse("aac"), Some(MediaFormat::Audio(AudioFormat::M4a)));
+        assert_eq!(MediaFormat::parse("wma"), None);
+
+        assert_eq!(AudioFormat::Opus.extension(), "opus");
+        assert_eq!(AudioFormat::M4a.content_type(), "audio/mp4");
+    }
+
+    #[test]
+    fn test_audio_options_args_and_cache_tag() {
+        let default = AudioOptions::default();
+        assert_eq!(default.ffmpeg_args(), vec!["-vn", "-c:a", "libmp3lame", "-b:a", "320k"]);
+        assert_eq!(default.cache_tag(), "");
+
+        let cbr = AudioOptions { format: AudioFormat::Mp3, bitrate: Some(128), quality: None, normalize: None, replaygain: false, trim_silence: None, split_chapters: false, clip: None };
+        assert_eq!(cbr.ffmpeg_args(), vec!["-vn", "-c:a", "libmp3lame", "-b:a", "128k"]);
+        assert_eq!(cbr.cache_tag(), " 128k");
+
+        let same_as_default = AudioOptions { format: AudioFormat::Mp3, bitrate: Some(320), quality: None, normalize: None, replaygain: false, trim_silence: None, split_chapters: false, clip: None };
+        assert_eq!(same_as_default.cache_tag(), "");
+
+        let vbr = AudioOptions { format: AudioFormat::Mp3, bitrate: None, quality: Some(2), normalize: None, replaygain: false, trim_silence: None, split_chapters: false, clip: None };
+        assert_eq!(vbr.ffmpeg_args(), vec!["-vn", "-c:a", "libmp3lame", "-q:a", "2"]);
+        assert_eq!(vbr.cache_tag(), " v2");
+
+        let flac = AudioOptions { format: AudioFormat::Flac, bitrate: Some(128), quality: None, normalize: None, replaygain: false, trim_silence: None, split_chapters: false, clip: None };
+        assert_eq!(flac.ffmpeg_args(), vec!["-vn", "-c:a", "flac"]);
+        assert_eq!(flac.cache_tag(), "");
+    }
+
+    #[test]
+    fn test_audio_passthrough() {
+        let opus = AudioOptions { format: AudioFormat::Opus, bitrate: None, quality: None, normalize: None, replaygain: false, trim_silence: None, split_chapters: false, clip: None };
+        assert_eq!(opus.passthrough_args(Some("opus"), None), Some(vec!["-vn".to_string(), "-c:a".to_string(), "copy".to_string()]));
+        assert_eq!(opus.passthrough_args(Some("mp4a.40.2"), None), None);
+        assert_eq!(opus.passthrough_args(None, None), None);
+
+        let reencode = AudioOptions { format: AudioFormat::Opus, bitrate: Some(96), quality: None, normalize: None, replaygain: false, trim_silence: None, split_chapters: false, clip: None };
+        assert_eq!(reencode.passthrough_args(Some("opus"), None), None);
+
+        let m4a = AudioOptions { format: AudioFormat::M4a, bitrate: None, quality: None, normalize: None, replaygain: false, trim_silence: None, split_chapters: false, clip: None };
+        assert!(m4a.passthrough_args(Some("mp4a.40.2"), None).is_some());
+        assert_eq!(AudioOptions::default().passthrough_args(Some("opus"), None), None);
+
+        let clip = Clip { start: 30, end: Some(60) };
+        assert_eq!(opus.passthrough_args(Some("opus"), Some(&clip)), None);
+        let trimmed = AudioOptions { trim_silence: Some(SilenceSettings { threshold_db: -50.0, min_duration: 1.0 }), ..opus };
+        assert_eq!(trimmed.passthrough_args(Some("opus"), None), None);
+    }
+
+    #[test]
+    fn test_video_options() {
+        assert_eq!(VideoOptions::default().ytdlp_selector(None), "bestvideo+bestaudio/best");
+        assert_eq!(VideoOptions::default().ytdlp_selector(Some(5000)), "bestvideo[filesize<=?5000]+bestaudio[filesize<=?5000]/best[filesize<=?5000]");
+
+        let options = VideoOptions { height: Some(480), fps: Some(30), max_filesize: Some(1000), ..VideoOptions::default() };
+        assert_eq!(options.ytdlp_selector(Some(5000)), "bestvideo[height<=?480][fps<=?30][filesize<=?1000]+bestaudio[filesize<=?1000]/best[height<=?480][fps<=?30][filesize<=?1000]");
+
+        let video = youtube_dl::SingleVideo { height: Some(720.0), fps: Some(29.97), ..Default::default() };
+        assert_eq!(VideoOptions::default().cache_tag(&video), "");
+        assert_eq!(options.cache_tag(&video), " 720p30");
+        assert_eq!(options.playlist_tag(), " 480p 30fps max1000");
+
+        assert_eq!(parse_filesize("50M"), Some(50 * 1024 * 1024));
+        assert_eq!(parse_filesize("1.5GB"), Some(1536 * 1024 * 1024));
+        assert_eq!(parse_filesize("2048"), Some(2048));
+        assert_eq!(parse_filesize("lots"), None);
+
+        let params = web::Query::<DownloaderParams>::from_query("format=mp4&height=720p&fps=60&max_filesize=200M").unwrap();
+        assert_eq!(params.video_options().unwrap(), VideoOptions { height: Some(720), fps: Some(60), max_filesize: Some(200 * 1024 * 1024), ..VideoOptions::default() });
+        assert!(web::Query::<DownloaderParams>::from_query("height=700").unwrap().video_options().is_err());
+        assert!(web::Query::<DownloaderParams>::from_query("max_filesize=0").unwrap().video_options().is_err());
+    }
+
+    #[test]
+    fn test_video_pipeline_args() {
+        let mp4 = VideoOptions::default();
+        assert_eq!(mp4.ffmpeg_args(Some("avc1.640028"), Some("mp4a.40.2")).join(" "), "-c:v copy -c:a copy -movflags +faststart");
+        assert_eq!(mp4.ffmpeg_args(Some("vp9"), Some("mp4a.40.2")).join(" "), "-c:v libx264 -crf 26 -preset fast -c:a copy -movflags +faststart");
 
-    return Ok(());
-}
+        let webm = VideoOptions { container: VideoContainer::Webm, ..VideoOptions::default() };
+        assert_eq!(webm.ffmpeg_args(Some("vp9"), Some("opus")).join(" "), "-c:v copy -c:a copy");
+        assert_eq!(webm.ffmpeg_args(Some("avc1"), Some("opus")).join(" "), "-c:v libvpx-vp9 -crf 32 -b:v 0 -deadline good -row-mt 1 -cpu-used 4 -c:a copy");
 
+        let hevc = VideoOptions { container: VideoContainer::Mkv, codec: Some(VideoCodec::H265), crf: Some(24), preset: Some("slow".to_string()), ..VideoOptions::default() };
+        assert_eq!(hevc.ffmpeg_args(Some("avc1"), Some("opus")).join(" "), "-c:v libx265 -crf 24 -preset slow -tag:v hvc1 -c:a copy");
+        assert_eq!(hevc.cache_tag(&youtube_dl::SingleVideo::default()), " x265 crf24 slow");
 
+        let params = web::Query::<DownloaderParams>::from_query("format=webm&vcodec=svtav1&preset=10&crf=30").unwrap();
+        let options = params.video_options().unwrap();
+        assert_eq!((options.container, options.codec, options.preset.as_deref()), (VideoContainer::Webm, Some(VideoCodec::Av1Svt), Some("10")));
+        assert!(web::Query::<DownloaderParams>::from_query("format=webm&vcodec=h264").unwrap().video_options().is_err());
+        assert!(web::Query::<DownloaderParams>::from_query("format=mp4&preset=slowest").unwrap().video_options().is_err());
 
-pub mod downloader {
-    use rustube::video_info::player_response::video_details::Thumbnail;
-    use youtube_dl::{YoutubeDl, SingleVideo};
-    use std::ops::Deref;
-    use std::{path::PathBuf, io::Error};
-    use std::{io, path};
-    use std::fs;
-    use std::env;
-    use std::io::{BufRead, BufReader};
-    use std::process::{Command, Stdio};
-    use rustube::*;
+        assert_eq!(web::Query::<DownloaderParams>::from_query("format=webm&crf=63").unwrap().video_options().unwrap().crf, Some(63));
+        assert_eq!(web::Query::<DownloaderParams>::from_query("format=mp4&crf=51").unwrap().video_options().unwrap().crf, Some(51));
+        assert!(web::Query::<DownloaderParams>::from_query("format=mp4&crf=52").unwrap().video_options().is_err());
+        assert!(web::Query::<DownloaderParams>::from_query("format=mkv&vcodec=h265&crf=60").unwrap().video_options().is_err());
+    }
 
-    pub async fn process_audio(filename: &String) -> Result<(), io::Error>{
-        let mut root = env::current_dir().unwrap();
-        root.extend(&["ffmpeg.exe"]);
-        
-        let mut cmd = Command::new(root.as_path())
-            .args([
-                "-i",
-                &format!("{}.opus", filename),
-                "-ab",
-                "320k",
-                &format!("{}.mp3", filename)
-            ]).stdout(Stdio::piped())
-            .spawn()
-            .unwrap();
-        {
-            let stdout = cmd.stdout.as_mut().unwrap();
-            let stdout_reader = BufReader::new(stdout);
-            let stdout_lines = stdout_reader.lines();
-            
-            for line in stdout_lines {
-                println!("Read: {:?}", line);
-            }
-        }
-        cmd.wait().unwrap();
-        Ok(())
+    #[test]
+    fn test_transcode_job_args() {
+        let job = transcoder::TranscodeJob::new(Path::new("in.webm"), Path::new("out.mp3"))
+            .audio_filter("volume=0.5")
+            .audio_filter("aresample=44100")
+            .output_args(["-c:a", "libmp3lame"]);
+
+        assert_eq!(job.ffmpeg_args().join(" "), "-y -hide_banner -i in.webm -af volume=0.5,aresample=44100 -c:a libmp3lame out.mp3");
+
+        assert_eq!(transcoder::parse_version("ffmpeg version 6.1.1-3ubuntu5 Copyright (c) 2000-2023").as_deref(), Some("6.1.1"));
+        assert_eq!(transcoder::parse_version("ffmpeg version n7.0 Copyright").as_deref(), Some("7.0"));
+        assert_eq!(transcoder::parse_version("ffmpeg version N-112345-gabcdef Copyright").as_deref(), Some("N-112345-gabcdef"));
+        assert_eq!(transcoder::parse_version("not ffmpeg"), None);
     }
-    pub async fn process_video(filename: &str) -> Result<(), io::Error>{
-        let mut root = env::current_dir().unwrap();
-        root.extend(&["ffmpeg.exe"]);
-        let mut cmd = Command::new(root.as_path())
-            .args([
-                "-i",
-                &format!("{}.webm", filename),
-                "-preset",
-                "fast",
-                "-crf",
-                "26",
-                &format!("{}.mp4", filename)
-            ]).stdout(Stdio::piped())
-            .spawn()
-            .unwrap();
-        {
-            let stdout = cmd.stdout.as_mut().unwrap();
-            let stdout_reader = BufReader::new(stdout);
-            let stdout_lines = stdout_reader.lines();
-            
-            for line in stdout_lines {
-                println!("Read: {:?}", line);
-            }
-        }
-        cmd.wait().unwrap();
-        Ok(())
+
+    struct FakeTranscoder {
+        jobs: std::sync::Mutex<Vec<transcoder::TranscodeJob>>,
     }
-    
-    pub async fn get_audio(url: &String) -> Result<String, io::Error> {
-        let _root: PathBuf = env::current_dir().unwrap();
-        let (ytdlp_path, _) = setup(&_root).unwrap();
 
-        let id = extract_id(&url);
+    impl transcoder::Transcoder for FakeTranscoder {
+        fn run<'a>(&'a self, job: &'a transcoder::TranscodeJob) -> transcoder::TranscodeFuture<'a> {
+            self.jobs.lock().unwrap().push(job.clone());
+            return Box::pin(async { Ok("".to_string()) });
+        }
+    }
 
-        return match id {
-            Some(value) => {
-                println!("Video ID: {:?}", value);
-                let video = download_audio(&value, &ytdlp_path, Some(false)).await.unwrap_or_default();
-                println!("Title: {:?}, channel: {:?}", video.title, video.channel);
+    /// Reports halfway progress, then holds the job until released.
+    struct GatedTranscoder {
+        release: tokio::sync::Notify,
+    }
 
-                Ok(video.url.unwrap())
-            },
-            None => {
-                Err(Error::new(io::ErrorKind::NotFound, "Video not found"))
-            }
+    impl transcoder::Transcoder for GatedTranscoder {
+        fn run<'a>(&'a self, _job: &'a transcoder::TranscodeJob) -> transcoder::TranscodeFuture<'a> {
+            Box::pin(async move {
+                let mut event = ProgressEvent::new(JobState::Transcoding);
+                event.percent = Some(50.0);
+                jobs::report_progress(event);
+                self.release.notified().await;
+                Ok("".to_string())
+            })
         }
     }
-    
-    pub async fn get_video(url: &String) -> Result<PathBuf> {
-        let _root: PathBuf = env::current_dir().unwrap();
-        let (_, tmp_path) = setup(&_root).unwrap();
-
-        let _id = extract_id(&url);
-        println!("{}", &url);
-        let i = Id::from_raw(&url)?;
-        let v = Video::from_id(i.into_owned()).await?;
 
-        let path = v.best_quality().unwrap().download_to_dir(tmp_path).await?;
+    /// Reads SSE chunks from `body` until `needle` shows up.
+    async fn read_events_until<B>(body: &mut B, needle: &str) -> String
+    where
+        B: actix_web::body::MessageBody + Unpin,
+        B::Error: std::fmt::Debug,
+    {
+        let mut seen = String::new();
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !seen.contains(needle) {
+                match std::future::poll_fn(|cx| std::pin::Pin::new(&mut *body).poll_next(cx)).await {
+                    Some(chunk) => seen.push_str(&String::from_utf8_lossy(&chunk.unwrap())),
+                    None => break,
+                }
+            }
+        }).await.expect("no such event in time");
 
-        return Ok(path);
+        seen
     }
 
-    pub async fn dl_get_audio(url: &String) -> Result<PathBuf, io::Error> {
-        let _root: PathBuf = env::current_dir().unwrap();
-        let (ytdlp_path, tmp_path) = setup(&_root).unwrap();
+    #[test]
+    fn test_job_events_during_transcode() {
+        use std::sync::Arc;
+        use transcoder::Transcoder;
 
-        let id = extract_id(&url);
+        actix_web::rt::System::new().block_on(async {
+            let store = Arc::new(JobStore::new(1));
+            let fake = Arc::new(GatedTranscoder { release: tokio::sync::Notify::new() });
 
-        return match id {
-            Some(value) => {
-                println!("Video ID: {:?}", value);
-                let vmetadata = get_metadata(&value, &ytdlp_path, None).await.unwrap();
+            let gated = fake.clone();
+            let id = store.clone().spawn("audio/mpeg", async move {
+                gated.run(&transcoder::TranscodeJob::new(Path::new("[abc].webm"), Path::new("Song [abc].mp3"))).await?;
+                Ok(PathBuf::from("temp/Song [abc].mp3"))
+            });
 
-                let c : super::Configuration = envy::from_env::<super::Configuration>().expect("Provide config.");
-                
-                let duration = vmetadata.duration.unwrap_or_default().as_f64().unwrap();
-                
+            let app = actix_web::test::init_service(App::new().app_data(web::Data::from(store.clone())).service(get_job_events)).await;
+            let req = TestRequest::get().uri(&format!("/jobs/{}/events", id)).to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "text/event-stream");
+            let mut body = resp.into_body();
 
-                if c.limit_duration && duration > (c.max_audio_duration_minutes as f64 * 60.0) {
-                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Audio duration exceeds maximum of {} hours", (c.max_audio_duration_minutes as f64 / 60.0))));
-                }
+            let seen = read_events_until(&mut body, r#""percent":50.0"#).await;
+            assert!(seen.contains(r#""state":"transcoding""#));
+            assert_eq!(store.get(&id).unwrap().state, JobState::Transcoding);
 
-                let nftitle: String = vmetadata.title.chars().filter(|c| c.is_ascii()).collect::<String>().replace("/", "").replace("|", "");
+            fake.release.notify_one();
+            let seen = read_events_until(&mut body, r#""state":"done""#).await;
+            assert!(seen.contains(r#""percent":100.0"#));
+        });
+    }
 
-                let fname = format!("{} [{}].mp3", nftitle, vmetadata.id);
+    #[test]
+    fn test_process_with_fake_transcoder() {
+        let fake = FakeTranscoder { jobs: std::sync::Mutex::new(Vec::new()) };
+        let rt = Runtime::new().unwrap();
 
-                let tmp_fpath = tmp_path.join(&fname);
-                if tmp_fpath.exists() {
-                    println!("File {} found in storage", tmp_fpath.to_str().unwrap());
+        let options = AudioOptions { format: AudioFormat::Opus, bitrate: None, quality: None, normalize: None, replaygain: false, trim_silence: None, split_chapters: false, clip: None };
+        rt.block_on(process_audio(&fake, Path::new("[abc].webm"), "Song [abc]", &options, Some("opus"), None)).unwrap();
 
-                    return Ok(tmp_fpath);
-                }
-                
-                let video = download_audio(&value, &ytdlp_path, Some(true)).await.unwrap_or_default();
-                let out_name: String = format!("[{}].opus", video.id);
+        let source = youtube_dl::SingleVideo { vcodec: Some("avc1.64001F".to_string()), acodec: Some("mp4a.40.2".to_string()), ..Default::default() };
+        rt.block_on(process_video(&fake, Path::new("[abc].mkv"), "Clip [abc]", &VideoOptions::default(), &source, Some(&Clip { start: 5, end: Some(15) }), &[])).unwrap();
 
-                let _fname = format!("{} [{}].opus", nftitle, video.id);
+        let jobs = fake.jobs.lock().unwrap();
+        assert_eq!(jobs[0].output, PathBuf::from("Song [abc].opus"));
+        assert_eq!(jobs[0].output_args, vec!["-vn", "-c:a", "copy"]);
+        assert_eq!(jobs[0].input_args, Vec::<String>::new());
+        assert_eq!(jobs[1].output, PathBuf::from("Clip [abc].mp4"));
+        assert_eq!(jobs[1].input_args, vec!["-ss", "5", "-t", "10"]);
+        assert_eq!(jobs[1].output_args, vec!["-c:v", "copy", "-c:a", "copy", "-movflags", "+faststart"]);
+    }
 
-                tokio::fs::rename(&out_name, &_fname).await.unwrap();
+    #[test]
+    fn test_track_tags() {
+        use lofty::tag::Accessor;
 
-                println!("processing file");
-                process_audio(&format!("{} [{}]", nftitle, video.id)).await.unwrap();
-                println!("moving file");
-                let p = move_video_to_temp(&_root, &fname).unwrap();
-                println!("move finished");
-                for path in fs::read_dir(&_root).unwrap() {
-                    let path = path.unwrap().path();
-                    match path.extension() {
-                        None => {
-                            
-                        }
-                        Some(ext) => {
-                            use std::ffi::OsStr;
-                            if ext == OsStr::new("opus") {
-                                fs::remove_file(path).unwrap();
-                            }
-                        }
-                    };
-                }
-                Ok(p)
-            },
-            None => {
-                Err(Error::new(io::ErrorKind::NotFound, "Video not found"))
-            }
-        }
-    }
+        let video = youtube_dl::SingleVideo {
+            id: "dQw4w9WgXcQ".to_string(),
+            title: "Rick Astley - Never Gonna Give You Up (Official Video)".to_string(),
+            track: Some("Never Gonna Give You Up".to_string()),
+            uploader: Some("Rick Astley".to_string()),
+            upload_date: Some("20091025".to_string()),
+            webpage_url: Some("https://www.youtube.com/watch?v=dQw4w9WgXcQ".to_string()),
+            ..Default::default()
+        };
+        let mut tags = tagging::TrackTags::from_video(&video, &MediaMetadata::from_single_video(&video));
+        assert_eq!(tags.title, "Never Gonna Give You Up");
+        assert_eq!(tags.artist, "Rick Astley");
+        assert_eq!(tags.date.as_deref(), Some("2009-10-25"));
+        assert_eq!(tagging::format_date("2009-10"), None);
 
-    pub async fn dl_get_video(url: &String, process: bool) -> Result<PathBuf, io::Error> {
-        let _root: PathBuf = env::current_dir().unwrap();
-        let (ytdlp_path, tmp_path) = setup(&_root).unwrap();
+        let mut png = Vec::new();
+        image::RgbImage::new(480, 360).write_to(&mut io::Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        let cover = tagging::square_cover(&png).unwrap();
+        let decoded = image::load_from_memory(&cover).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (360, 360));
+        tags.cover = Some(cover);
 
-        let id = extract_id(&url);
+        let tag = tagging::id3_tag(&tags);
+        assert_eq!(tag.title().as_deref(), Some("Never Gonna Give You Up"));
+        assert_eq!(tag.get_text(&lofty::id3::v2::FrameId::Valid("TDRC".into())), Some("2009-10-25"));
+        assert_eq!(tag.get_user_text("VIDEO_ID"), Some("dQw4w9WgXcQ"));
+        assert!(tag.get(&lofty::id3::v2::FrameId::Valid("APIC".into())).is_some());
 
-        return match id {
-            Some(value) => {
-                println!("Video ID: {:?}", value);
-                let vmetadata = get_metadata(&value, &ytdlp_path, Some(false)).await.unwrap();
-               
-                let c : super::Configuration = envy::from_env::<super::Configuration>().expect("Provide config.");
+        let comments = tagging::vorbis_comments(&tags).unwrap();
+        assert_eq!(comments.get("DATE"), Some("2009-10-25"));
+        assert_eq!(comments.get("VIDEO_ID"), Some("dQw4w9WgXcQ"));
+        assert_eq!(lofty::ogg::OggPictureStorage::pictures(&comments).len(), 1);
 
-                let duration = vmetadata.duration.unwrap_or_default().as_f64().unwrap();
-                
-                if c.limit_duration && duration > (c.max_video_duration_minutes as f64 * 60.0) {
-                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Video duration exceeds maximum of {} minutes", c.max_video_duration_minutes)));
-                }
+        let ilst = tagging::ilst(&tags);
+        assert_eq!(ilst.title().as_deref(), Some("Never Gonna Give You Up"));
+        assert!(ilst.get(&lofty::mp4::AtomIdent::Fourcc(*b"covr")).is_some());
+    }
 
-                let nftitle: String = vmetadata.title.chars().filter(|c| c.is_ascii()).collect::<String>().replace("/", "").replace("|", "");
+    #[test]
+    fn test_loudnorm() {
+        let log = "[Parsed_loudnorm_0 @ 0x5581] \n{\n\t\"input_i\" : \"-27.61\",\n\t\"input_tp\" : \"-4.47\",\n\t\"input_lra\" : \"18.06\",\n\t\"input_thresh\" : \"-39.20\",\n\t\"output_i\" : \"-14.00\",\n\t\"normalization_type\" : \"dynamic\",\n\t\"target_offset\" : \"0.58\"\n}\n";
+        let measured = loudness::parse_measurement(log).unwrap();
+        assert_eq!(measured.input_i, -27.61);
+        assert_eq!(measured.target_offset, 0.58);
+        assert_eq!(loudness::parse_measurement("no json here"), None);
 
-                let fname = format!("{} [{}].mp4", nftitle, vmetadata.id);
+        let target = LoudnessTarget { integrated: -14.0, true_peak: -1.0, lra: 11.0 };
+        assert_eq!(target.filter(&measured), "loudnorm=I=-14:TP=-1:LRA=11:measured_I=-27.61:measured_TP=-4.47:measured_LRA=18.06:measured_thresh=-39.2:offset=0.58:linear=true");
 
-                let tmp_fpath = tmp_path.join(format!("{}.mp4", &fname));
-                if tmp_fpath.exists() {
-                    println!("File {} found in storage", tmp_fpath.to_str().unwrap());
+        let normalized = AudioOptions { normalize: Some(target), ..AudioOptions::default() };
+        assert_eq!(normalized.cache_tag(), " norm-14_-1_11");
+        assert_eq!(normalized.passthrough_args(Some("mp3"), None), None);
 
-                    return Ok(tmp_fpath);
-                }
-                
-                download_video(&value, &ytdlp_path, Some(true)).await.unwrap_or_default();
-                println!("Title: {:?}, channel: {:?}", vmetadata.title, vmetadata.channel);
+        let params = web::Query::<DownloaderParams>::from_query("format=mp3&normalize=true").unwrap();
+        assert_eq!(params.audio_options().unwrap().normalize.map(|t| t.integrated), Some(-14.0));
+        assert_eq!(web::Query::<DownloaderParams>::from_query("normalize=0").unwrap().audio_options().unwrap().normalize, None);
+        assert!(web::Query::<DownloaderParams>::from_query("normalize=maybe").unwrap().audio_options().is_err());
 
-                let out_name: String = format!("[{}].webm", &vmetadata.id);
-                let _fnamewext = format!("{} [{}]", &nftitle, &vmetadata.id);
-                let _fname = format!("{}.webm", &_fnamewext);
+        let meta = CacheMeta { loudness: Some(measured), ..CacheMeta::default() };
+        let dir = env::temp_dir().join(format!("r_webaudioprov_meta_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let media = dir.join("Song [abc] norm-14_-1_11.mp3");
+        meta.write(&media).unwrap();
+        assert!(dir.join("Song [abc] norm-14_-1_11.mp3.json").exists());
+        assert_eq!(CacheMeta::read(&media), Some(meta));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-                tokio::fs::rename(&out_name, &_fname).await.unwrap();
+    #[test]
+    fn test_replaygain() {
+        let measured = loudness::LoudnessMeasurement { input_i: -12.0, input_tp: -0.5, input_lra: 6.0, input_thresh: -22.0, target_offset: 0.0 };
+        let gain = loudness::ReplayGain::track(&measured);
+        assert_eq!(gain.tag_values(), vec![
+            ("REPLAYGAIN_TRACK_GAIN", "-6.00 dB".to_string()),
+            ("REPLAYGAIN_TRACK_PEAK", "0.944061".to_string()),
+        ]);
+        assert_eq!(gain.r128_values(), vec![("R128_TRACK_GAIN", "-2816".to_string())]);
 
-                let mut ext: &str = "webm";
+        let quiet = loudness::LoudnessMeasurement { input_i: -22.0, input_tp: -6.0, ..measured };
+        let (album_i, album_tp) = loudness::album_loudness(&[(measured, 200.0), (quiet, 200.0)]).unwrap();
+        assert!((album_i - -14.6).abs() < 0.05, "{}", album_i);
+        assert_eq!(album_tp, -0.5);
+        assert_eq!(loudness::album_loudness(&[]), None);
 
-                if process {
-                    println!("processing file");
-                    ext = "mp4";
-                    process_video(&_fnamewext).await.unwrap();
-                }
-                println!("moving file");
-                let p = move_video_to_temp(&_root, &format!("{} [{}].{}", &nftitle, &vmetadata.id, ext)).unwrap();
-                println!("move finished");
-                for path in fs::read_dir(&_root).unwrap() {
-                    let path = path.unwrap().path();
-                    match path.extension() {
-                        None => {}
-                        Some(ext) => {
-                            use std::ffi::OsStr;
-                            if ext == OsStr::new("webm") {
-                                fs::remove_file(path).unwrap();
-                            }
-                        }
-                    };
-                }
-                Ok(p)
-            },
-            None => {
-                Err(Error::new(io::ErrorKind::NotFound, "Video not found"))
-            }
-        }
+        let album = gain.with_album(album_i, album_tp);
+        assert_eq!(album.tag_values()[2], ("REPLAYGAIN_ALBUM_GAIN", "-3.40 dB".to_string()));
+
+        let mut tag = lofty::id3::v2::Id3v2Tag::new();
+        tagging::replaygain_id3(&mut tag, &album);
+        assert_eq!(tag.get_user_text("REPLAYGAIN_TRACK_GAIN"), Some("-6.00 dB"));
+        assert_eq!(tag.get_user_text("REPLAYGAIN_ALBUM_PEAK"), Some("0.944061"));
+
+        let params = web::Query::<DownloaderParams>::from_query("format=flac&replaygain=true").unwrap();
+        assert!(params.audio_options().unwrap().replaygain);
     }
 
-    pub fn extract_id(link : &String) -> Option<String> {
-        let idx_of_id = link.find("v=").unwrap_or(0);
-        let id : String = link.chars().skip(idx_of_id+2).take_while(|c| *c != '&' && *c != ' ' && *c != '\r' && *c!='\n').collect();
+    #[test]
+    fn test_replaygain_copy() {
+        let dir = env::temp_dir().join(format!("r_webaudioprov_rg_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
 
-        if id.len() != 11 {
-            return None;
-        }
+        // 8 kHz mono 16 bit WAV with 100 ms of silence.
+        let data = vec![0u8; 1600];
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((36 + data.len() as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(8000u32.to_le_bytes());
+        wav.extend(16000u32.to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(&data);
 
-        return Some(id);
-    }
+        let media = dir.join("Song [abc].wav");
+        fs::write(&media, &wav).unwrap();
+        let measured = loudness::LoudnessMeasurement { input_i: -12.0, input_tp: -0.5, input_lra: 6.0, input_thresh: -22.0, target_offset: 0.0 };
+        CacheMeta { output_loudness: Some(measured), ..CacheMeta::default() }.write(&media).unwrap();
 
-    pub async fn download_audio(id: &String, ytdl_path: &PathBuf, download : Option<bool>) -> Option<SingleVideo> {
-        let url = format!("https://www.youtube.com/watch?v={}", id);
+        let fake = FakeTranscoder { jobs: std::sync::Mutex::new(Vec::new()) };
+        let tagged = Runtime::new().unwrap().block_on(ensure_replaygain(&fake, &media, AudioFormat::Wav, 0.1)).unwrap();
 
-        println!("Downloading video: {}", url);
+        assert_eq!(tagged, dir.join("Song [abc] rg.wav"));
+        assert_eq!(fs::read(&media).unwrap(), wav);
+        assert!(fs::read(&tagged).unwrap().windows(21).any(|w| w == b"REPLAYGAIN_TRACK_GAIN"));
+        assert_eq!(CacheMeta::read(&tagged).and_then(|m| m.output_loudness), Some(measured));
+        assert!(fake.jobs.lock().unwrap().is_empty());
 
-        let dl = download.unwrap_or_default();
+        let mut files = vec![("01 - Song [abc].wav".to_string(), tagged.clone())];
+        let mut staged = Vec::new();
+        stage_album_gain(&dir, "list", AudioFormat::Wav, &mut files, &mut staged).unwrap();
+        assert_eq!(files[0].1, dir.join(".list 0 01 - Song [abc].wav"));
+        assert_eq!(staged, vec![files[0].1.clone()]);
+        assert!(fs::read(&files[0].1).unwrap().windows(21).any(|w| w == b"REPLAYGAIN_ALBUM_GAIN"));
 
-        let output = YoutubeDl::new(&url)
-            .youtube_dl_path(ytdl_path)
-            .socket_timeout("15")
-            .format("bestaudio")
-            .extract_audio(dl)
-            .output_template("[%(id)s].%(ext)s")
-            .download(dl)
-            .run_async().await;
-            
-        return match output {
-            Ok(v) => {
-                Some(v.into_single_video().unwrap())
-            },
-            Err(_) => {
-                None
-            },
-        }
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    pub async fn download_video(id: &String, ytdl_path: &PathBuf, download : Option<bool>) -> Option<SingleVideo> {
-        let url = format!("https://www.youtube.com/watch?v={}", id);
+    #[test]
+    fn test_clip() {
+        let clip = Clip { start: 90, end: Some(120) };
+        assert_eq!(clip.section(), "*90-120");
+        assert_eq!(clip.cache_tag(), " 90-120s");
+        assert_eq!(clip.length(100.0), 10.0);
+        assert_eq!(Clip { start: 30, end: None }.section(), "*30-inf");
+        assert_eq!(clip_length(Some(&clip), 600.0).unwrap(), 30.0);
+        assert!(clip_length(Some(&clip), 60.0).is_err());
+        assert_eq!(clip_length(None, 600.0).unwrap(), 600.0);
 
-        println!("Downloading video: {}", url);
+        let params = web::Query::<DownloaderParams>::from_query("start=1:30&end=120").unwrap();
+        assert_eq!(params.audio_options().unwrap().clip, Some(clip));
+        assert_eq!(params.audio_options().unwrap().cache_tag(), " 90-120s");
+        assert!(web::Query::<DownloaderParams>::from_query("start=120&end=90").unwrap().audio_options().is_err());
+        assert_eq!(web::Query::<DownloaderParams>::from_query("start=0").unwrap().video_options().unwrap().clip, None);
 
-        let dl = download.unwrap_or_default();
+        let mut params = web::Query::<DownloaderParams>::from_query("format=mp4").unwrap().into_inner();
+        params.apply_link_start(parse_link("https://youtu.be/PpjdTwQwWWY?t=1m").and_then(|l| l.start));
+        assert_eq!(params.video_options().unwrap().clip, Some(Clip { start: 60, end: None }));
 
-        let output = YoutubeDl::new(&url)
-            .youtube_dl_path(ytdl_path)
-            .socket_timeout("15")
-            .format("bestaudio+bestvideo")
-            .output_template("[%(id)s].%(ext)s")
-            .download(dl)
-            .run_async().await;
-            
-        return match output {
-            Ok(v) => {
-                Some(v.into_single_video().unwrap())
-            },
-            Err(_) => {
-                None
-            },
-        }
+        let mut explicit = web::Query::<DownloaderParams>::from_query("start=5").unwrap().into_inner();
+        explicit.apply_link_start(Some(60));
+        assert_eq!(explicit.audio_options().unwrap().clip.map(|c| c.start), Some(5));
     }
 
-    pub async fn get_metadata(id:  &String, ytdl_path: &PathBuf, video: Option<bool>) -> Option<SingleVideo> {
-        let url = format!("https://www.youtube.com/watch?v={}", id);
+    #[test]
+    fn test_silence_trim() {
+        let log = "  Duration: 00:03:30.00, start: 0.000000, bitrate: 128 kb/s\n\
+            [silencedetect @ 0x55d0] silence_start: -0.01\n\
+            [silencedetect @ 0x55d0] silence_end: 4.5 | silence_duration: 4.51\n\
+            [silencedetect @ 0x55d0] silence_start: 101.2\n\
+            [silencedetect @ 0x55d0] silence_end: 102.7 | silence_duration: 1.5\n\
+            [silencedetect @ 0x55d0] silence_start: 195\n";
+
+        assert_eq!(silence::parse_intervals(log), vec![(0.0, Some(4.5)), (101.2, Some(102.7)), (195.0, None)]);
+        let cut = silence::trim_from_log(log, 210.0);
+        assert_eq!(cut, silence::SilenceTrim { leading: 4.5, trailing: 15.0 });
+        assert_eq!(cut.ffmpeg_input_args(30.0, 210.0), vec!["-ss", "34.500", "-t", "190.500"]);
 
-        let opt = if video.is_some_and(| x | x == true) { "bestaudio+bestvideo" } else { "bestaudio" };
+        // Newer ffmpeg closes silence running into the end of the input.
+        let closed = "[silencedetect @ 0x1] silence_start: 200\n[silencedetect @ 0x1] silence_end: 210 | silence_duration: 10\n";
+        assert_eq!(silence::trim_from_log(closed, 210.0), silence::SilenceTrim { leading: 0.0, trailing: 10.0 });
+        assert!(silence::trim_from_log("[silencedetect @ 0x1] silence_start: 0\n", 210.0).is_empty());
 
-        let output = YoutubeDl::new(&url)
-            .youtube_dl_path(ytdl_path)
-            .socket_timeout("15")
-            .format(opt)
-            .run_async().await;
-        
-        return match output {
-            Ok(v) => {
-                Some(v.into_single_video().unwrap())
-            },
-            Err(_) => {
-                None
-            },
-        }
+        let params = web::Query::<DownloaderParams>::from_query("trim_silence=true").unwrap();
+        let options = params.audio_options().unwrap();
+        assert_eq!(options.trim_silence, Some(SilenceSettings { threshold_db: -50.0, min_duration: 1.0 }));
+        assert_eq!(options.cache_tag(), " trim-50_1");
     }
-    
-    pub fn move_video_to_temp(root_dir : &PathBuf, filename: &String) -> Result<PathBuf, io::Error> {
-        let mut temp_dir = root_dir.clone();
-        temp_dir.extend(&["temp"]);
 
-        let mut filepath = root_dir.clone();
-        filepath.extend(&[&filename]);
+    #[test]
+    fn test_chapters() {
+        let description = "Full album, recorded live at 12:30 in the afternoon.\n\
+            \n\
+            Tracklist:\n\
+            1. 0:00 - Intro\n\
+            2. [03:25] Second Song (feat. Someone)\n\
+            Last One 1:02:10\n\
+            \n\
+            https://example.com/merch";
 
-        if !filepath.exists() {
-            return Err(io::Error::new(io::ErrorKind::InvalidData,format!("{} does not exist", filename)));
-        }
-        
-        fs::create_dir_all(&temp_dir)?;
+        let chapters = parse_description_chapters(description, 4000.0);
+        assert_eq!(chapters, vec![
+            Chapter { title: "Intro".to_string(), start: 0.0, end: 205.0 },
+            Chapter { title: "Second Song (feat. Someone)".to_string(), start: 205.0, end: 3730.0 },
+            Chapter { title: "Last One".to_string(), start: 3730.0, end: 4000.0 },
+        ]);
+        assert_eq!(chapters[1].ffmpeg_input_args(), vec!["-ss", "205.000", "-t", "3525.000"]);
 
-        return match fs::rename(filepath, temp_dir.join(&filename)) {
-            Ok(_) => {
-                let p = temp_dir.join(&filename);
-                println!("Successfully moved {} to {}", filename, p.to_str().unwrap());
-                Ok(p)
-            },
-            Err(e) => {
-                println!("Error: {}", e);
-                Err(e)
-            },
+        assert!(parse_description_chapters("Starts at 1:00\nThen 2:00", 300.0).is_empty());
+        assert!(parse_description_chapters("0:00 Only one", 300.0).is_empty());
+
+        let video = youtube_dl::SingleVideo {
+            chapters: Some(vec![
+                youtube_dl::Chapter { start_time: Some(0.0), end_time: Some(60.0), title: Some("A".to_string()) },
+                youtube_dl::Chapter { start_time: Some(60.0), end_time: None, title: None },
+            ]),
+            description: Some(description.to_string()),
+            ..Default::default()
         };
+        assert_eq!(video_chapters(&video, 100.0)[1], Chapter { title: "Chapter 2".to_string(), start: 60.0, end: 100.0 });
+
+        assert!(web::Query::<DownloaderParams>::from_query("split=chapters").unwrap().audio_options().unwrap().split_chapters);
+        assert!(web::Query::<DownloaderParams>::from_query("split=chapters&start=10").unwrap().audio_options().is_err());
+        assert!(web::Query::<DownloaderParams>::from_query("split=chapters&trim_silence=true").unwrap().audio_options().is_err());
+        assert_eq!(web::Query::<DownloaderParams>::from_query("split=chapters&trim_silence=false").unwrap().audio_options().unwrap().trim_silence, None);
+        assert!(web::Query::<DownloaderParams>::from_query("split=chapters&format=mp4").unwrap().audio_options().is_err());
+        assert!(web::Query::<DownloaderParams>::from_query("split=pages").unwrap().audio_options().is_err());
     }
 
-    pub fn setup(root : &PathBuf) -> Option<(PathBuf, PathBuf)> {
-        if let Some(proj_dirs) = directories::ProjectDirs::from("me", "lukasz26671", "r_webaudioprov") {
-            let dir = proj_dirs.data_dir();
-            let mut ytdlp: PathBuf = env::current_dir().unwrap();
-            ytdlp.extend(&["yt-dlp.exe"]);
+    #[test]
+    fn test_waveform_peaks() {
+        let samples: Vec<i16> = vec![0, 1000, -1000, 32767, -32768, 256, 512, -512, 0, 0];
+        let pcm: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
 
-            fs::create_dir_all(dir).unwrap();
-            if !dir.join("yt-dlp.exe").exists() {
-                match fs::copy(ytdlp,dir.join("yt-dlp.exe").as_path()) {
-                    Ok(_) => {
-                        println!("Successfully copied");
-                    },
-                    Err(err) => {
-                        panic!("failed to copy, {}", err);
-                    },
-                }
-            }
-            let p = dir.join("yt-dlp.exe");
-            return Some((p, root.join("temp")));
-        }
-        return None;
+        let peaks = waveform::peaks(&pcm[..], samples.len() as u64, 3).unwrap();
+        assert_eq!(peaks.samples_per_pixel, 4);
+        assert_eq!(peaks.length, 3);
+        assert_eq!(peaks.data, vec![-4, 127, -128, 2, 0, 0]);
+        assert_eq!(serde_json::to_value(&peaks).unwrap()["version"], 2);
+
+        assert_eq!(waveform::cache_path(Path::new("tmp/Song [abc].mp3"), 800), PathBuf::from("tmp/Song [abc].mp3.peaks800.json"));
     }
 
-    pub async fn get_metadata_resp(url: &String) -> Result<MediaMetadata> {
-        
-        let id = Id::from_raw(&url)?;
+    #[test]
+    fn test_thumbnail() {
+        let mut jpeg = Vec::new();
+        image::RgbImage::new(640, 360).write_to(&mut io::Cursor::new(&mut jpeg), image::ImageFormat::Jpeg).unwrap();
 
-        let descrambler = VideoFetcher::from_id(id.into_owned())?.fetch().await?;
+        let options = web::Query::<ThumbnailParams>::from_query("size=320&square=true&format=webp").unwrap().thumbnail_options().unwrap();
+        assert_eq!(options, ThumbnailOptions { size: Some(320), square: true, format: ThumbnailFormat::Webp });
+        assert_eq!(options.cache_name("dQw4w9WgXcQ"), "dQw4w9WgXcQ thumbnail 320px square.webp");
 
-        let info = descrambler.video_info();
-        let details = descrambler.video_details();
-        
-        let result = MediaMetadata {
-            title: (details.title.to_string()),
-            author: (details.author.to_string()),
-            short_desc: (details.short_description.to_string()),
-            id: (details.video_id.to_string()),
-            age_restricted: info.is_age_restricted,
-            is_private: details.is_private,
-            length: details.length_seconds,
-            thumbnails: Some(details.thumbnails.to_vec())
-        };
+        let rendered = thumbnails::render(&jpeg, &options).unwrap();
+        assert_eq!(image::guess_format(&rendered).unwrap(), image::ImageFormat::WebP);
+        let decoded = image::load_from_memory(&rendered).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (320, 320));
 
-        Ok(result)
-    }
+        let resized = thumbnails::render(&jpeg, &ThumbnailOptions { size: Some(320), ..ThumbnailOptions::default() }).unwrap();
+        let decoded = image::load_from_memory(&resized).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (320, 180));
 
-    #[derive(serde::Serialize, serde::Deserialize, Debug)]
-    pub struct MediaMetadata {
-        pub title: String,
-        pub author: String,
-        pub short_desc: String,
-        pub id: String,
-        pub length: u64,
-        pub age_restricted: bool,
-        pub is_private: bool,
-        pub thumbnails: Option<Vec<Thumbnail>>,
+        let original = thumbnails::render(&jpeg, &ThumbnailOptions { size: Some(1280), ..ThumbnailOptions::default() }).unwrap();
+        assert_eq!(image::load_from_memory(&original).unwrap().width(), 640);
+
+        assert!(web::Query::<ThumbnailParams>::from_query("size=0").unwrap().thumbnail_options().is_err());
+        assert!(web::Query::<ThumbnailParams>::from_query("format=gif").unwrap().thumbnail_options().is_err());
+
+        let video = youtube_dl::SingleVideo {
+            id: "dQw4w9WgXcQ".to_string(),
+            thumbnails: Some(vec![
+                youtube_dl::Thumbnail { url: Some("https://i.ytimg.com/vi/dQw4w9WgXcQ/default.jpg".to_string()), width: Some(120.0), ..Default::default() },
+                youtube_dl::Thumbnail { url: Some("https://i.ytimg.com/vi/dQw4w9WgXcQ/hqdefault.jpg".to_string()), width: Some(480.0), ..Default::default() },
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(thumbnails::known_source("dQw4w9WgXcQ"), None);
+        thumbnails::remember_source(&MediaMetadata::from_single_video(&video));
+        assert_eq!(thumbnails::known_source("dQw4w9WgXcQ").as_deref(), Some("https://i.ytimg.com/vi/dQw4w9WgXcQ/hqdefault.jpg"));
     }
 
-}
-#[cfg(test)]
-mod test {
-    use std::env;
-    use super::*;
-    use tokio::runtime::Runtime;
+    #[test]
+    fn test_subtitles() {
+        let vtt = "WEBVTT\nKind: captions\nLanguage: en\n\n\
+            00:00:00.000 --> 00:00:02.000 align:start position:0%\n \nhello<00:00:00.500><c> world</c>\n\n\
+            00:00:02.000 --> 00:00:02.010 align:start position:0%\nhello world\n \n\n\
+            00:00:02.010 --> 00:01:04.500 align:start position:0%\nhello world\nfish &amp; chips\n\n";
 
+        let cues = subtitles::parse(vtt);
+        assert_eq!(cues.len(), 3);
+        assert_eq!(cues[0], subtitles::Cue { start: 0.0, end: 2.0, text: "hello world".to_string() });
+
+        let cues = subtitles::dedupe_rolling(cues);
+        assert_eq!(cues.len(), 2);
+        assert_eq!(cues[1].text, "fish & chips");
+
+        assert_eq!(subtitles::to_srt(&cues), "1\n00:00:00,000 --> 00:00:02,000\nhello world\n\n2\n00:00:02,010 --> 00:01:04,500\nfish & chips\n\n");
+        assert_eq!(SubtitleFormat::Txt.render(&cues), "hello world\nfish & chips\n");
+        let roundtrip = subtitles::parse(&SubtitleFormat::Vtt.render(&cues));
+        assert_eq!(roundtrip, cues);
+        assert_eq!(subtitles::parse(&subtitles::to_srt(&cues)), cues);
+
+        let available = vec!["de".to_string(), "en-US".to_string()];
+        assert_eq!(subtitles::pick_language(&available, "en").as_deref(), Some("en-US"));
+        assert_eq!(subtitles::pick_language(&available, "DE").as_deref(), Some("de"));
+        assert_eq!(subtitles::pick_language(&available, "fr"), None);
+    }
 
     #[test]
-    fn test_extract_id_passing() {
-        let link = "https://www.youtube.com/watch?v=JIvKgSyvtxI&fbclid=abcdssf".to_owned();
+    fn test_subtitle_tracks() {
+        let params = web::Query::<DownloaderParams>::from_query("format=mp4&subs=en,%20pt-BR,en").unwrap();
+        let options = params.video_options().unwrap();
+        assert_eq!(options.subtitles, vec!["en", "pt-BR"]);
+        assert_eq!(options.playlist_tag(), " subs en,pt-BR");
+        assert!(web::Query::<DownloaderParams>::from_query("subs=en;rm").unwrap().video_options().is_err());
 
-        let id = extract_id(&link).unwrap();
-        assert_eq!(id, "JIvKgSyvtxI");
+        let tracks = vec![
+            subtitles::SubtitleTrack { path: PathBuf::from("[abc subs] en.srt"), language: "en".to_string(), automatic: false },
+            subtitles::SubtitleTrack { path: PathBuf::from("[abc subs] pt-BR.srt"), language: "pt-BR".to_string(), automatic: true },
+        ];
+        assert_eq!(options.subtitle_args(&tracks), vec![
+            "-map", "0:v:0", "-map", "0:a:0?", "-map", "1:0", "-map", "2:0", "-c:s", "mov_text",
+            "-metadata:s:s:0", "language=eng", "-metadata:s:s:0", "title=en",
+            "-metadata:s:s:1", "language=por", "-metadata:s:s:1", "title=pt-BR (auto-generated)",
+        ]);
+        assert_eq!(VideoOptions { container: VideoContainer::Mkv, ..VideoOptions::default() }.subtitle_args(&tracks)[9], "srt");
+        assert!(options.subtitle_args(&[]).is_empty());
+        assert_eq!(subtitles::iso639_2("fil"), "fil");
+        assert_eq!(subtitles::iso639_2("xx-YY"), "und");
+
+        let cues = vec![
+            subtitles::Cue { start: 1.0, end: 4.0, text: "before".to_string() },
+            subtitles::Cue { start: 9.0, end: 12.0, text: "across the start".to_string() },
+            subtitles::Cue { start: 18.0, end: 25.0, text: "across the end".to_string() },
+        ];
+        let shifted = subtitles::shift(&cues, 10.0, 10.0);
+        assert_eq!(shifted.iter().map(|c| (c.start, c.end)).collect::<Vec<_>>(), vec![(0.0, 2.0), (8.0, 10.0)]);
+        let unknown = subtitles::shift(&cues, 0.0, 0.0);
+        assert_eq!(unknown.iter().map(|c| (c.start, c.end)).collect::<Vec<_>>(), vec![(1.0, 4.0), (9.0, 12.0), (18.0, 25.0)]);
     }
+
     #[test]
-    fn test_extract_id_passing2() {
-        let link = "https://www.youtube.com/watch?v=PpjdTwQwWWY".to_owned();
+    fn test_stream_proxy() {
+        assert_eq!(streaming::content_type(Some("webm"), Some("opus")), "audio/webm");
+        assert_eq!(streaming::content_type(Some("m4a"), Some("mp4a.40.2")), "audio/mp4");
+        assert!(streaming::is_expired("https://rr1.googlevideo.com/videoplayback?expire=1000&id=x", 990));
+        assert!(!streaming::is_expired("https://rr1.googlevideo.com/videoplayback?expire=5000&id=x", 990));
+        assert!(!streaming::is_expired("https://example.com/audio", 990));
+        assert!(streaming::is_stale_status(reqwest::StatusCode::FORBIDDEN));
 
-        let id = extract_id(&link).unwrap();
-        assert_eq!(id, "PpjdTwQwWWY");
+        actix_web::rt::System::new().block_on(async {
+            // Stands in for googlevideo, answering ranges of ten known bytes.
+            let upstream = HttpServer::new(|| App::new().route("/audio", web::get().to(|req: HttpRequest| async move {
+                let body: &[u8] = b"0123456789";
+                match req.headers().get(header::RANGE).and_then(|r| r.to_str().ok()) {
+                    Some("bytes=2-5") => HttpResponse::PartialContent()
+                        .insert_header((header::CONTENT_RANGE, "bytes 2-5/10"))
+                        .content_type("application/octet-stream")
+                        .body(&body[2..6]),
+                    _ => HttpResponse::Ok().content_type("application/octet-stream").body(body),
+                }
+            }))).workers(1).bind(("127.0.0.1", 0)).unwrap();
+            let addr = upstream.addrs()[0];
+            actix_web::rt::spawn(upstream.run());
+
+            let streams = web::Data::new(StreamCache::new());
+            let id = parse_link("PpjdTwQwWWY").unwrap().id;
+            streams.insert(&id, streaming::StreamSource {
+                url: format!("http://{}/audio", addr),
+                content_type: "audio/webm".to_string(),
+                headers: vec![("User-Agent".to_string(), "test".to_string())],
+            });
+
+            let app = actix_web::test::init_service(App::new().app_data(streams.clone()).service(get_stream_id)).await;
+            let req = actix_web::test::TestRequest::get().uri("/stream_id/PpjdTwQwWWY?proxy=true")
+                .insert_header((header::RANGE, "bytes=2-5"))
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "audio/webm");
+            assert_eq!(resp.headers().get(header::CONTENT_RANGE).unwrap(), "bytes 2-5/10");
+            assert_eq!(resp.headers().get(header::ACCEPT_RANGES).unwrap(), "bytes");
+            assert_eq!(actix_web::test::read_body(resp).await, "2345");
+
+            let req = actix_web::test::TestRequest::get().uri("/stream_id/PpjdTwQwWWY?proxy=true").to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(actix_web::body::MessageBody::size(resp.response().body()), actix_web::body::BodySize::Sized(10));
+        });
     }
 
     #[test]
-    fn test_extract_id_failing() {
-        let link = "https://www.youtube.com/watch?v=gibb".to_owned();
+    fn test_downloader_params_audio_options() {
+        let params = web::Query::<DownloaderParams>::from_query("format=mp3&bitrate=128").unwrap();
+        assert_eq!(params.audio_options().unwrap().bitrate, Some(128));
 
-        let id = extract_id(&link);
+        let params = web::Query::<DownloaderParams>::from_query("quality=v0").unwrap();
+        assert_eq!(params.audio_options().unwrap().quality, Some(0));
 
-        assert!(id.is_none());
+        let params = web::Query::<DownloaderParams>::from_query("bitrate=100").unwrap();
+        assert!(params.audio_options().is_err());
+
+        let params = web::Query::<DownloaderParams>::from_query("bitrate=128&quality=v2").unwrap();
+        assert!(params.audio_options().is_err());
     }
+
     #[test]
-    fn test_extract_id_failing2() {
-        let link = "https://www.youtube.com/watch?v=gibberishtoofuckinglong".to_owned();
+    fn test_audio_bitrate_cap() {
+        let options = |format: AudioFormat, bitrate: Option<u16>, quality: Option<u8>| AudioOptions { format, bitrate, quality, ..AudioOptions::default() };
 
-        let id = extract_id(&link);
+        // MAX_AUDIO_BITRATE=128
+        assert_eq!(options(AudioFormat::Mp3, None, None).cap_bitrate(128).unwrap().bitrate, Some(128));
+        assert_eq!(options(AudioFormat::Ogg, None, None).cap_bitrate(128).unwrap().bitrate, Some(128));
+        assert_eq!(options(AudioFormat::Mp3, Some(96), None).cap_bitrate(128).unwrap().bitrate, Some(96));
+        assert!(options(AudioFormat::Mp3, Some(192), None).cap_bitrate(128).is_err());
+        assert!(options(AudioFormat::Opus, None, Some(0)).cap_bitrate(128).is_err());
+        assert!(options(AudioFormat::Mp3, None, Some(5)).cap_bitrate(128).is_err());
+        assert_eq!(options(AudioFormat::Mp3, None, Some(6)).cap_bitrate(128).unwrap().quality, Some(6));
+        assert_eq!(options(AudioFormat::Flac, None, None).cap_bitrate(128).unwrap().bitrate, None);
 
-        assert!(id.is_none());
+        // Below every supported bitrate the default cannot be lowered far enough.
+        assert!(options(AudioFormat::Mp3, None, None).cap_bitrate(64).is_err());
+        assert_eq!(options(AudioFormat::Mp3, None, None).cap_bitrate(320).unwrap().bitrate, None);
     }
 
     #[test]
     fn test_yt_extract_id() {
         let link = "https://www.youtube.com/watch?v=PpjdTwQwWWY".to_owned();
 
-        let id = extract_id(&link).unwrap();
+        let id = parse_link(&link).unwrap().id;
         let mut ytdlp: PathBuf = env::current_dir().unwrap();
         ytdlp.extend(&["yt-dlp.exe"]);
 